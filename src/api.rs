@@ -0,0 +1,21 @@
+use axum::extract::FromRequestParts;
+use axum::Json;
+use http::request::Parts;
+use http::StatusCode;
+use serde_json::Value;
+use crate::registry::{bearer_token, verify_token};
+
+/// Guards the API endpoints, which expect `SYNKRONIZED_API_TOKEN` as a bearer token.
+pub struct ApiToken;
+
+impl<S> FromRequestParts<S> for ApiToken
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        verify_token(bearer_token(&parts.headers), "SYNKRONIZED_API_TOKEN")?;
+        Ok(ApiToken)
+    }
+}
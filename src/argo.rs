@@ -1,16 +1,89 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
 use derivative::Derivative;
-use kube::{Api, Client, CustomResource};
-use kube::api::{Patch, PatchParams};
+use kube::{Api, Client, CustomResource, ResourceExt};
+use kube::api::{ListParams, Patch, PatchParams};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use crate::{SynkronizedProject, helm};
+use crate::{helm, utils, PublishedImage};
+use crate::config::{SyncConfig, SyncTrigger};
+use crate::project::{HelmOptions, SynkronizedProject};
+use crate::source::SourceRepository;
 
-const ARGO_NAMESPACE: &str = "argocd";
+pub(crate) const ARGO_NAMESPACE: &str = "argocd";
+const VALUES_REF: &str = "values";
+pub(crate) const FIELD_MANAGER: &str = "synkronized";
+/// Field manager of Applications applied by earlier versions
+const LEGACY_FIELD_MANAGER: &str = "kubectl-light";
+/// Last deployed image of each container, as a JSON object keyed by container name
+pub(crate) const IMAGES_ANNOTATION: &str = "synkronized.vaughn.sh/images";
+/// Repository whose config created the Application, the only one allowed to update it
+pub(crate) const REPOSITORY_ANNOTATION: &str = "synkronized.vaughn.sh/repository";
+/// The published image the Application was last deployed for, as JSON
+pub(crate) const PUBLISHED_IMAGE_ANNOTATION: &str = "synkronized.vaughn.sh/published-image";
+/// synkronized-charts version of the template the Application was last deployed with
+pub(crate) const CHART_VERSION_ANNOTATION: &str = "synkronized.vaughn.sh/chart-version";
+/// SynkronizedApp rendering the Application, as `namespace/name`, absent for synkronized.yaml apps
+pub(crate) const SYNKRONIZED_APP_ANNOTATION: &str = "synkronized.vaughn.sh/synkronized-app";
 
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Helm {
-    values: String
+    /// Values as a YAML string
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<String>,
+    /// Values as structured data, so Argo can diff them field by field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values_object: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    parameters: Vec<HelmParameter>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    file_parameters: Vec<HelmFileParameter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    value_files: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    ignore_missing_value_files: bool
+}
+
+impl Helm {
+    /// Helm settings of a source, with `values` and any parameters the image is injected through
+    /// preceding the app's own parameters.
+    pub fn new(values: &serde_yaml::Value, options: &HelmOptions, mut parameters: Vec<HelmParameter>) -> anyhow::Result<Helm> {
+        let (values, values_object) = match options.values_object {
+            true => (None, Some(serde_json::to_value(values)?)),
+            false => (Some(serde_yaml::to_string(values)?), None)
+        };
+        parameters.extend(options.parameters.iter().cloned());
+
+        Ok(Helm {
+            values,
+            values_object,
+            parameters,
+            file_parameters: options.file_parameters.clone(),
+            release_name: options.release_name.clone(),
+            value_files: options.value_files.clone(),
+            ignore_missing_value_files: options.ignore_missing_value_files,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HelmParameter {
+    pub(crate) name: String,
+    pub(crate) value: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) force_string: bool
+}
+
+/// A Helm parameter set to the contents of a file, e.g. a certificate
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HelmFileParameter {
+    pub(crate) name: String,
+    pub(crate) path: String
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
@@ -19,8 +92,105 @@ pub struct Source {
     #[serde(rename="repoURL")]
     repo_url: String,
     target_revision: String,
-    chart: String,
-    helm: Helm
+    /// Chart name, for sources in a Helm repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chart: Option<String>,
+    /// Directory in the repository, for sources in a git repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    helm: Option<Helm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kustomize: Option<Kustomize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    directory: Option<Directory>,
+    /// Name other sources of a multi-source Application use to refer to this one, e.g. `$values`
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    reference: Option<String>
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Kustomize {
+    /// Image overrides in `kustomize edit set image` form, e.g. `app=ghcr.io/owner/app:v1`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Directory {
+    #[serde(default)]
+    pub(crate) recurse: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) include: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) exclude: Option<String>
+}
+
+impl Source {
+    /// A synkronized-charts template
+    pub fn template(template: helm::Template, helm: Helm) -> Source {
+        Source {
+            repo_url: helm::CHART_REPO.to_string(),
+            chart: Some(template.name),
+            target_revision: template.version,
+            helm: Some(helm),
+            ..Default::default()
+        }
+    }
+
+    /// A Helm chart living in the app's own git repository
+    pub fn git_chart(repo_url: String, path: String, revision: String, helm: Helm) -> Source {
+        Source {
+            repo_url,
+            path: Some(path),
+            target_revision: revision,
+            helm: Some(helm),
+            ..Default::default()
+        }
+    }
+
+    /// Reads value files through the `$values` source of a multi-source Application
+    pub fn with_value_files(mut self, files: &[String]) -> Source {
+        if let Some(helm) = self.helm.as_mut() {
+            helm.value_files.extend(files.iter()
+                .map(|file| format!("${}/{}", VALUES_REF, file.trim_start_matches('/'))));
+        }
+        self
+    }
+
+    /// The app's git repository, referenced as `$values` by the other sources
+    pub fn values_ref(repo_url: String, revision: String) -> Source {
+        Source {
+            repo_url,
+            target_revision: revision,
+            reference: Some(VALUES_REF.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// A kustomization in the app's git repository, with image overrides
+    pub fn kustomize(repo_url: String, path: String, revision: String, images: Vec<String>) -> Source {
+        Source {
+            repo_url,
+            path: Some(path),
+            target_revision: revision,
+            kustomize: Some(Kustomize { images }),
+            ..Default::default()
+        }
+    }
+
+    /// Plain manifests in the app's git repository
+    pub fn directory(repo_url: String, path: String, revision: String, directory: Directory) -> Source {
+        Source {
+            repo_url,
+            path: Some(path),
+            target_revision: revision,
+            directory: Some(directory),
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, Derivative)]
@@ -51,27 +221,189 @@ pub struct Automated {
 }
 
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema, Default, Derivative)]
-#[kube(group = "argoproj.io", version = "v1alpha1", kind = "Application", namespaced)]
+#[kube(group = "argoproj.io", version = "v1alpha1", kind = "Application", namespaced, status = "ApplicationStatus")]
 #[serde(rename_all = "camelCase")]
 pub struct Spec {
     #[derivative(Default(value="default"))]
     project: String,
-    source: Source,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Source>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<Source>,
     destination: Destination,
     sync_policy: SyncPolicy
 }
 
+/// What Argo reports about the Application
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplicationStatus {
+    pub(crate) sync: Option<SyncStatus>,
+    pub(crate) health: Option<HealthStatus>,
+    /// Every resource of the app, with its own sync and health
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) resources: Vec<ResourceStatus>,
+    /// Previous syncs, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) history: Vec<RevisionHistory>,
+    pub(crate) operation_state: Option<OperationState>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) conditions: Vec<ApplicationCondition>,
+    pub(crate) summary: Option<ApplicationSummary>,
+    /// When Argo last compared the Application with its sources
+    pub(crate) reconciled_at: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncStatus {
+    pub(crate) status: SyncStatusCode,
+    /// Revision the Application was compared against
+    pub(crate) revision: Option<String>,
+    /// Revision of each source of a multi-source Application
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) revisions: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, PartialEq, Eq)]
+pub enum SyncStatusCode {
+    Synced,
+    OutOfSync,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthStatus {
+    pub(crate) status: HealthStatusCode,
+    pub(crate) message: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, PartialEq, Eq)]
+pub enum HealthStatusCode {
+    Healthy,
+    Progressing,
+    Degraded,
+    Suspended,
+    Missing,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceStatus {
+    #[serde(default)]
+    pub(crate) group: String,
+    pub(crate) version: String,
+    pub(crate) kind: String,
+    pub(crate) namespace: Option<String>,
+    pub(crate) name: String,
+    pub(crate) status: Option<SyncStatusCode>,
+    pub(crate) health: Option<HealthStatus>,
+    /// Whether Argo would delete the resource on a pruning sync
+    #[serde(default)]
+    pub(crate) requires_pruning: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionHistory {
+    pub(crate) id: i64,
+    pub(crate) revision: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) revisions: Vec<String>,
+    pub(crate) deploy_started_at: Option<String>,
+    pub(crate) deployed_at: Option<String>,
+    pub(crate) initiated_by: Option<InitiatedBy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct InitiatedBy {
+    pub(crate) username: Option<String>,
+    #[serde(default)]
+    pub(crate) automated: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OperationState {
+    pub(crate) phase: OperationPhase,
+    pub(crate) message: Option<String>,
+    pub(crate) started_at: Option<String>,
+    pub(crate) finished_at: Option<String>,
+    pub(crate) sync_result: Option<SyncOperationResult>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncOperationResult {
+    pub(crate) revision: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) revisions: Vec<String>,
+    /// Outcome of each resource the operation touched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) resources: Vec<ResourceResult>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceResult {
+    #[serde(default)]
+    pub(crate) group: String,
+    pub(crate) version: String,
+    pub(crate) kind: String,
+    pub(crate) namespace: Option<String>,
+    pub(crate) name: String,
+    /// `Synced`, `SyncFailed`, `Pruned` or `PruneSkipped`
+    pub(crate) status: Option<String>,
+    pub(crate) message: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplicationCondition {
+    /// e.g. `ComparisonError` or `SyncError`
+    #[serde(rename = "type")]
+    pub(crate) condition_type: String,
+    pub(crate) message: String,
+    pub(crate) last_transition_time: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplicationSummary {
+    /// Every image running in the app's resources
+    #[serde(default)]
+    pub(crate) images: Vec<String>,
+    #[serde(default, rename = "externalURLs", skip_serializing_if = "Vec::is_empty")]
+    pub(crate) external_urls: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, PartialEq, Eq)]
+pub enum OperationPhase {
+    Running,
+    Terminating,
+    Failed,
+    Error,
+    Succeeded,
+    #[serde(other)]
+    Unknown,
+}
+
 impl Application {
-    pub fn create(project: SynkronizedProject, template: helm::Template) -> Application {
+    /// Creates the Application, as a multi-source Application when given several sources.
+    pub fn create(project: &SynkronizedProject, mut sources: Vec<Source>) -> Application {
+        let source = match sources.len() {
+            1 => sources.pop(),
+            _ => None
+        };
+
         Application::new(&project.synkronized.name, Spec {
-            source: Source {
-                repo_url: helm::CHART_REPO.to_string(),
-                chart: template.name,
-                target_revision: template.version,
-                helm: Helm {
-                    values: serde_yaml::to_string(&project.config).unwrap()
-                }
-            },
+            source,
+            sources,
             sync_policy: SyncPolicy {
                 sync_options: vec!["CreateNamespace=true".to_string()],
                 ..Default::default()
@@ -84,13 +416,261 @@ impl Application {
         })
     }
 
-    pub async fn apply(self, client: &Client) -> anyhow::Result<()> {
-        let ss_apply = PatchParams::apply("kubectl-light").force();
-        let data: serde_json::Value = serde_json::to_value(&self)?;
+    /// Records the deployed images so that later publishes of other containers can keep them.
+    pub fn record_images(&mut self, images: &BTreeMap<String, String>) {
+        self.metadata.annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(IMAGES_ANNOTATION.to_string(), serde_json::to_string(images).unwrap());
+    }
+
+    /// Marks the Application as belonging to `repo`.
+    pub fn record_owner(&mut self, repo: &SourceRepository) {
+        self.metadata.annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(REPOSITORY_ANNOTATION.to_string(), repo.to_string());
+    }
+
+    /// Records the publish that deployed the Application, so it can be rendered again later.
+    pub fn record_published(&mut self, image: &PublishedImage) {
+        self.metadata.annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(PUBLISHED_IMAGE_ANNOTATION.to_string(), serde_json::to_string(image).unwrap());
+    }
+
+    /// Records the template version the Application was rendered with, so it can be rendered
+    /// again without picking up newer chart releases.
+    pub fn record_chart_version(&mut self, version: &str) {
+        self.metadata.annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(CHART_VERSION_ANNOTATION.to_string(), version.to_string());
+    }
+
+    pub fn chart_version(&self) -> Option<&str> {
+        self.annotations().get(CHART_VERSION_ANNOTATION).map(String::as_str)
+    }
+
+    /// Marks the Application as rendered from the SynkronizedApp `app`, rather than a synkronized.yaml.
+    pub fn record_synkronized_app(&mut self, app: &str) {
+        self.metadata.annotations
+            .get_or_insert_with(BTreeMap::new)
+            .insert(SYNKRONIZED_APP_ANNOTATION.to_string(), app.to_string());
+    }
+
+    /// The SynkronizedApp the Application is rendered from, as `namespace/name`
+    pub fn synkronized_app(&self) -> Option<&str> {
+        self.annotations().get(SYNKRONIZED_APP_ANNOTATION).map(String::as_str)
+    }
+
+    /// The publish recorded on the Application, `None` for Applications deployed before it was recorded.
+    pub fn published(&self) -> anyhow::Result<Option<PublishedImage>> {
+        match self.annotations().get(PUBLISHED_IMAGE_ANNOTATION) {
+            Some(image) => Ok(Some(serde_json::from_str(image)?)),
+            None => Ok(None)
+        }
+    }
+
+    /// Every Application synkronized has deployed.
+    pub async fn managed(client: &Client) -> anyhow::Result<Vec<Application>> {
         let api: Api<Application> = Api::namespaced(client.clone(), ARGO_NAMESPACE);
-        api.patch(&self.metadata.name.unwrap(), &ss_apply, &Patch::Apply(data)).await?;
+        let applications = api.list(&ListParams::default()).await?;
+        Ok(applications.items.into_iter().filter(|app| app.owner().is_some()).collect())
+    }
 
+    /// The Application as it is in the cluster, if it exists.
+    pub async fn live(client: &Client, name: &str) -> anyhow::Result<Option<Application>> {
+        let api: Api<Application> = Api::namespaced(client.clone(), ARGO_NAMESPACE);
+        Ok(api.get_opt(name).await?)
+    }
+
+    /// Images recorded on the Application, empty when none were.
+    pub fn images(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        match self.annotations().get(IMAGES_ANNOTATION) {
+            Some(images) => Ok(serde_json::from_str(images)?),
+            None => Ok(BTreeMap::new())
+        }
+    }
+
+    /// Repository recorded as the Application's owner, `None` for Applications created
+    /// before ownership was recorded.
+    pub fn owner(&self) -> Option<&str> {
+        self.annotations().get(REPOSITORY_ANNOTATION).map(String::as_str)
+    }
+
+    /// Whether the Application was applied by synkronized before its owner was recorded
+    pub fn legacy_managed(&self) -> bool {
+        self.managed_fields().iter().any(|fields| fields.manager.as_deref() == Some(LEGACY_FIELD_MANAGER))
+    }
+
+    /// Starts a sync of the applied revisions, as configured by `config.trigger`.
+    pub async fn sync(&self, client: &Client, config: &SyncConfig) -> anyhow::Result<()> {
+        let name = self.name_any();
+        let revisions: Vec<String> = self.spec.source.iter()
+            .chain(self.spec.sources.iter())
+            .map(|source| source.target_revision.clone())
+            .collect();
+        // Multi-source Applications sync each source at its own revision
+        let (revision, revisions) = match revisions.as_slice() {
+            [revision] => (Some(revision.clone()), vec![]),
+            _ => (None, revisions)
+        };
+
+        match config.trigger {
+            SyncTrigger::None => return Ok(()),
+            SyncTrigger::Operation => {
+                let operation = serde_json::json!({
+                    "operation": {
+                        "initiatedBy": { "username": FIELD_MANAGER },
+                        "sync": {
+                            "revision": revision,
+                            "revisions": revisions,
+                            "prune": config.prune,
+                            "syncOptions": config.options,
+                        }
+                    }
+                });
+                // A merge patch keeps the operation out of the fields owned through apply, which
+                // Argo clears once the operation has finished
+                let api: Api<Application> = Api::namespaced(client.clone(), ARGO_NAMESPACE);
+                api.patch(&name, &PatchParams { field_manager: Some(FIELD_MANAGER.to_string()), ..Default::default() }, &Patch::Merge(&operation)).await?;
+            },
+            SyncTrigger::Api => {
+                let url = config.argocd_url.as_ref()
+                    .ok_or(anyhow::anyhow!("rollout.sync.argocdUrl is required for the api sync trigger."))?;
+                let token = std::env::var("ARGOCD_API_TOKEN")
+                    .map_err(|_| anyhow::anyhow!("ARGOCD_API_TOKEN is required for the api sync trigger."))?;
+
+                reqwest::Client::new()
+                    .post(format!("{}/api/v1/applications/{}/sync", url.trim_end_matches('/'), name))
+                    .bearer_auth(token)
+                    .json(&serde_json::json!({
+                        "revision": revision,
+                        "revisions": revisions,
+                        "prune": config.prune,
+                        "syncOptions": { "items": config.options },
+                    }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+
+        tracing::info!("Started a sync of {} ({:?})", name, config.trigger);
         Ok(())
     }
 
-}
\ No newline at end of file
+    /// Server-side applies the Application. Fields another manager set are only taken over when
+    /// `force` is set, otherwise the conflicting fields are returned as an `ApplyConflict`.
+    pub async fn apply(&self, client: &Client, force: bool) -> anyhow::Result<Applied> {
+        let name = self.name_any();
+        let data: serde_json::Value = serde_json::to_value(self)?;
+        let api: Api<Application> = Api::namespaced(client.clone(), ARGO_NAMESPACE);
+        let before = api.get_opt(&name).await?;
+
+        let mut overridden = vec![];
+        let after = match api.patch(&name, &PatchParams::apply(FIELD_MANAGER), &Patch::Apply(&data)).await {
+            Ok(after) => after,
+            Err(kube::Error::Api(e)) if e.code == 409 => {
+                let managers = conflicting_managers(&e.message);
+
+                // Fields from before the field manager was renamed are ours to take back, anything
+                // else, including a message naming no manager, is someone else's edit
+                let legacy = !managers.is_empty() && managers.iter().all(|m| m == LEGACY_FIELD_MANAGER);
+                if !force && !legacy {
+                    return Err(ApplyConflict { app: name, message: e.message }.into());
+                }
+                tracing::warn!("Overriding conflicting fields of {}: {}", name, e.message);
+                overridden = managers;
+                api.patch(&name, &PatchParams::apply(FIELD_MANAGER).force(), &Patch::Apply(&data)).await?
+            },
+            Err(e) => return Err(e.into())
+        };
+
+        let mut changes = vec![];
+        if let Some(before) = &before {
+            let (before, after) = (serde_json::to_value(before)?, serde_json::to_value(&after)?);
+            for (field, pointer) in [("spec", "/spec"), ("metadata.annotations", "/metadata/annotations")] {
+                utils::diff_json(field, before.pointer(pointer), after.pointer(pointer), &mut changes);
+            }
+        }
+        for change in &changes {
+            tracing::info!("{} {}: {:?} -> {:?}", name, change.path, change.before, change.after);
+        }
+
+        Ok(Applied {
+            created: before.is_none(),
+            name,
+            changes,
+            overridden,
+            sync_error: None,
+        })
+    }
+
+}
+
+/// The outcome of applying an Application
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Applied {
+    pub(crate) name: String,
+    pub(crate) created: bool,
+    /// Fields changed by the apply, empty for a new Application
+    pub(crate) changes: Vec<utils::Change>,
+    /// Field managers whose fields were taken over
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) overridden: Vec<String>,
+    /// Why the sync after the apply couldn't be started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sync_error: Option<String>,
+}
+
+/// An apply refused because other field managers own some of the fields.
+#[derive(Debug)]
+pub struct ApplyConflict {
+    pub(crate) app: String,
+    /// The API server's description of the conflicting fields and their managers
+    pub(crate) message: String,
+}
+
+impl Display for ApplyConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} was changed outside of synkronized: {}", self.app, self.message)
+    }
+}
+
+impl std::error::Error for ApplyConflict {}
+
+/// Managers named in a conflict message, e.g. `conflict with "argocd-server" using argoproj.io/v1alpha1: .spec.syncPolicy`
+fn conflicting_managers(message: &str) -> Vec<String> {
+    let mut managers: Vec<String> = message.split("with \"")
+        .skip(1)
+        .filter_map(|s| s.split('"').next())
+        .map(str::to_string)
+        .collect();
+    managers.dedup();
+    managers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicting_managers_of_single_conflict() {
+        let message = r#"Apply failed with 1 conflict: conflict with "kubectl-light" using argoproj.io/v1alpha1: .spec.source.targetRevision"#;
+        assert_eq!(conflicting_managers(message), vec!["kubectl-light"]);
+    }
+
+    #[test]
+    fn conflicting_managers_of_several_managers() {
+        let message = "Apply failed with 3 conflicts: conflicts with \"kubectl-light\" using argoproj.io/v1alpha1:\n\
+            - .spec.project\n\
+            - .spec.source.helm.values\n\
+            conflict with \"argocd-server\" using argoproj.io/v1alpha1: .spec.syncPolicy.automated";
+        assert_eq!(conflicting_managers(message), vec!["kubectl-light", "argocd-server"]);
+    }
+
+    #[test]
+    fn conflicting_managers_of_unknown_message() {
+        assert!(conflicting_managers("Operation cannot be fulfilled on applications.argoproj.io").is_empty());
+    }
+}
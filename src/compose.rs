@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use crate::project::Container;
+use crate::source::SourceProvider;
+
+/// The subset of the Compose specification that maps onto the chart templates.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ComposeFile {
+    #[serde(default)]
+    pub(crate) services: BTreeMap<String, Service>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Service {
+    pub(crate) image: Option<String>,
+    pub(crate) build: Option<Value>,
+    pub(crate) entrypoint: Option<Command>,
+    pub(crate) command: Option<Command>,
+    #[serde(default)]
+    pub(crate) ports: Vec<Port>,
+    #[serde(default)]
+    pub(crate) environment: Environment,
+    #[serde(default)]
+    pub(crate) volumes: Vec<Volume>,
+    pub(crate) healthcheck: Option<Healthcheck>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Command {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+impl Command {
+    /// Compose splits string commands into words like a shell would, without running one. Unbalanced
+    /// quotes are left for a shell to report when the container starts.
+    fn to_args(&self) -> Vec<String> {
+        match self {
+            Command::Shell(command) => shlex::split(command).unwrap_or_else(|| {
+                tracing::warn!("Unable to split compose command {}, running it with /bin/sh", command);
+                vec!["/bin/sh".to_string(), "-c".to_string(), command.clone()]
+            }),
+            Command::Exec(args) => args.clone(),
+        }
+    }
+}
+
+/// Either the short `"127.0.0.1:8080:80/udp"` syntax or the long mapping syntax.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Port {
+    Number(u16),
+    Short(String),
+    Long {
+        target: u16,
+        published: Option<Value>,
+        protocol: Option<String>,
+    },
+}
+
+impl Port {
+    /// The port inside the container, and its protocol
+    fn target(&self) -> Option<(u16, String)> {
+        match self {
+            Port::Number(port) => Some((*port, "TCP".to_string())),
+            Port::Short(port) => {
+                let (port, protocol) = port.split_once('/').unwrap_or((port, "tcp"));
+                let target = port.rsplit(':').next()?;
+                // Ranges like 3000-3005 only expose their first port
+                let target = target.split('-').next()?.parse().ok()?;
+                Some((target, protocol.to_uppercase()))
+            },
+            Port::Long { target, protocol, .. } => Some((*target, protocol.as_deref().unwrap_or("tcp").to_uppercase())),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Environment {
+    List(Vec<String>),
+    Map(BTreeMap<String, Option<Value>>),
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::List(vec![])
+    }
+}
+
+impl Environment {
+    /// Variables with a value, in chart `env` form. Compose lets a bare `KEY` pass through
+    /// the host's value, which has no meaning in the cluster, so those are dropped.
+    fn to_env(&self) -> Vec<Value> {
+        let pairs: Vec<(String, String)> = match self {
+            Environment::List(vars) => vars.iter()
+                .filter_map(|v| v.split_once('='))
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            Environment::Map(vars) => vars.iter()
+                .filter_map(|(k, v)| match v {
+                    Some(Value::String(v)) => Some((k.clone(), v.clone())),
+                    Some(Value::Number(v)) => Some((k.clone(), v.to_string())),
+                    Some(Value::Bool(v)) => Some((k.clone(), v.to_string())),
+                    _ => None
+                })
+                .collect(),
+        };
+
+        pairs.into_iter()
+            .map(|(name, value)| mapping([("name", Value::from(name)), ("value", Value::from(value))]))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Volume {
+    Short(String),
+    Long {
+        #[serde(rename = "type")]
+        volume_type: Option<String>,
+        source: Option<String>,
+        target: String,
+        read_only: Option<bool>,
+    },
+}
+
+impl Volume {
+    /// Named volumes become persistent volumes of the chart. Bind mounts point at paths on
+    /// the Docker host and have no equivalent, so they are skipped.
+    fn to_mount(&self) -> Option<Value> {
+        let (source, target, read_only) = match self {
+            Volume::Short(volume) => {
+                let mut parts = volume.split(':');
+                let source = parts.next()?;
+                let target = parts.next()?;
+                (source.to_string(), target.to_string(), parts.next() == Some("ro"))
+            },
+            Volume::Long { volume_type, source, target, read_only } => {
+                if volume_type.as_deref().is_some_and(|t| t != "volume") {
+                    return None;
+                }
+                (source.clone()?, target.clone(), read_only.unwrap_or(false))
+            }
+        };
+
+        if source.starts_with('.') || source.starts_with('/') || source.starts_with('~') {
+            tracing::warn!("Skipping bind mount {} of compose service", source);
+            return None;
+        }
+
+        Some(mapping([
+            ("name", Value::from(source)),
+            ("mountPath", Value::from(target)),
+            ("readOnly", Value::from(read_only)),
+        ]))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Healthcheck {
+    pub(crate) test: Option<Command>,
+    pub(crate) interval: Option<String>,
+    pub(crate) timeout: Option<String>,
+    pub(crate) retries: Option<u32>,
+    pub(crate) start_period: Option<String>,
+    #[serde(default)]
+    pub(crate) disable: bool,
+}
+
+impl Healthcheck {
+    /// An exec probe in chart `healthcheck` form
+    fn to_probe(&self) -> Option<Value> {
+        if self.disable {
+            return None;
+        }
+
+        let command = match self.test.as_ref()? {
+            Command::Shell(test) => vec!["/bin/sh".to_string(), "-c".to_string(), test.clone()],
+            Command::Exec(test) => match test.split_first() {
+                Some((kind, args)) if kind == "CMD" => args.to_vec(),
+                Some((kind, args)) if kind == "CMD-SHELL" => vec!["/bin/sh".to_string(), "-c".to_string(), args.join(" ")],
+                _ => return None
+            }
+        };
+
+        let mut probe = mapping([("command", Value::from(command))]);
+        let fields = [
+            ("periodSeconds", self.interval.as_deref().and_then(parse_duration)),
+            ("timeoutSeconds", self.timeout.as_deref().and_then(parse_duration)),
+            ("failureThreshold", self.retries.map(u64::from)),
+            ("initialDelaySeconds", self.start_period.as_deref().and_then(parse_duration)),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                probe.as_mapping_mut().unwrap().insert(Value::from(key), Value::from(value));
+            }
+        }
+        Some(probe)
+    }
+}
+
+/// Parses Compose durations such as `1m30s` or `500ms` into whole seconds, rounding up.
+fn parse_duration(duration: &str) -> Option<u64> {
+    let mut millis = 0u64;
+    let mut number = String::new();
+    let mut chars = duration.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let value: u64 = number.parse().ok()?;
+        number.clear();
+        millis += match c {
+            'h' => value * 3_600_000,
+            'm' if chars.peek() == Some(&'s') => { chars.next(); value },
+            'm' => value * 60_000,
+            's' => value * 1000,
+            _ => return None
+        };
+    }
+
+    // A bare number is seconds
+    if !number.is_empty() {
+        millis += number.parse::<u64>().ok()? * 1000;
+    }
+    Some(millis.div_ceil(1000))
+}
+
+fn mapping<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Mapping(entries.into_iter().map(|(k, v)| (Value::from(k), v)).collect::<Mapping>())
+}
+
+impl ComposeFile {
+    pub async fn load(source: &dyn SourceProvider, repo: &str, path: &str, reference: Option<&str>) -> Result<ComposeFile> {
+        let contents = source.fetch_file(repo, path, reference).await?;
+        serde_yaml::from_str(&contents).map_err(|e| anyhow!("Unable to parse {}: {}", path, e))
+    }
+
+    /// Translates each service into a container of the chart. Services that are built from the
+    /// repository are bound to the package named after the service, while services running an
+    /// upstream image (databases, caches, ...) keep that image.
+    pub fn containers(&self) -> Vec<Container> {
+        self.services.iter()
+            .map(|(name, service)| {
+                let mut values = Mapping::new();
+                let ports: Vec<Value> = service.ports.iter()
+                    .filter_map(Port::target)
+                    .map(|(port, protocol)| mapping([("containerPort", Value::from(port)), ("protocol", Value::from(protocol))]))
+                    .collect();
+                let env = service.environment.to_env();
+                let volumes: Vec<Value> = service.volumes.iter().filter_map(Volume::to_mount).collect();
+
+                if let Some(entrypoint) = &service.entrypoint {
+                    values.insert(Value::from("command"), Value::from(entrypoint.to_args()));
+                }
+                if let Some(command) = &service.command {
+                    values.insert(Value::from("args"), Value::from(command.to_args()));
+                }
+                if !ports.is_empty() {
+                    values.insert(Value::from("ports"), Value::from(ports));
+                }
+                if !env.is_empty() {
+                    values.insert(Value::from("env"), Value::from(env));
+                }
+                if !volumes.is_empty() {
+                    values.insert(Value::from("volumes"), Value::from(volumes));
+                }
+                if let Some(probe) = service.healthcheck.as_ref().and_then(Healthcheck::to_probe) {
+                    values.insert(Value::from("healthcheck"), probe);
+                }
+
+                Container {
+                    name: name.clone(),
+                    package: None,
+                    image: match service.build {
+                        Some(_) => None,
+                        None => service.image.clone(),
+                    },
+                    values,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(yaml: &str) -> Option<(u16, String)> {
+        serde_yaml::from_str::<Port>(yaml).unwrap().target()
+    }
+
+    #[test]
+    fn string_commands_are_split_like_a_shell() {
+        let command = Command::Shell(r#"sh -c "npm run migrate && npm start""#.to_string());
+        assert_eq!(command.to_args(), vec!["sh", "-c", "npm run migrate && npm start"]);
+    }
+
+    #[test]
+    fn unbalanced_commands_run_in_a_shell() {
+        let command = Command::Shell(r#"echo "oops"#.to_string());
+        assert_eq!(command.to_args(), vec!["/bin/sh", "-c", r#"echo "oops"#]);
+    }
+
+    #[test]
+    fn short_ports() {
+        assert_eq!(port("80"), Some((80, "TCP".to_string())));
+        assert_eq!(port("\"8080:80\""), Some((80, "TCP".to_string())));
+        assert_eq!(port("\"127.0.0.1:5353:53/udp\""), Some((53, "UDP".to_string())));
+    }
+
+    #[test]
+    fn port_ranges_expose_their_first_port() {
+        assert_eq!(port("\"3000-3005\""), Some((3000, "TCP".to_string())));
+        assert_eq!(port("\"9000-9005:3000-3005\""), Some((3000, "TCP".to_string())));
+    }
+
+    #[test]
+    fn long_ports() {
+        assert_eq!(port("{target: 80, published: 8080}"), Some((80, "TCP".to_string())));
+        assert_eq!(port("{target: 53, protocol: udp}"), Some((53, "UDP".to_string())));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("1m30s"), Some(90));
+        assert_eq!(parse_duration("500ms"), Some(1));
+        assert_eq!(parse_duration("1h"), Some(3600));
+        assert_eq!(parse_duration("10"), Some(10));
+        assert_eq!(parse_duration("5d"), None);
+    }
+
+    #[test]
+    fn named_volumes_are_mounted() {
+        let mount = Volume::Short("data:/var/lib/postgresql/data:ro".to_string()).to_mount().unwrap();
+        assert_eq!(mount["name"], "data");
+        assert_eq!(mount["mountPath"], "/var/lib/postgresql/data");
+        assert_eq!(mount["readOnly"], true);
+
+        let volume: Volume = serde_yaml::from_str("{type: volume, source: cache, target: /cache}").unwrap();
+        assert_eq!(volume.to_mount().unwrap()["name"], "cache");
+    }
+
+    #[test]
+    fn bind_mounts_are_skipped() {
+        assert!(Volume::Short("./config:/etc/app".to_string()).to_mount().is_none());
+        assert!(Volume::Short("/var/run/docker.sock:/var/run/docker.sock".to_string()).to_mount().is_none());
+
+        let volume: Volume = serde_yaml::from_str("{type: bind, source: ./src, target: /app}").unwrap();
+        assert!(volume.to_mount().is_none());
+    }
+
+    #[test]
+    fn list_environments() {
+        let environment: Environment = serde_yaml::from_str("[RUST_LOG=debug, URL=http://a?b=c, PASSTHROUGH]").unwrap();
+        let env = environment.to_env();
+        assert_eq!(env.len(), 2);
+        assert_eq!(env[0]["name"], "RUST_LOG");
+        assert_eq!(env[0]["value"], "debug");
+        assert_eq!(env[1]["value"], "http://a?b=c");
+    }
+
+    #[test]
+    fn map_environments() {
+        let environment: Environment = serde_yaml::from_str("{PORT: 8080, DEBUG: true, NAME: app, PASSTHROUGH: null}").unwrap();
+        let env = environment.to_env();
+        assert_eq!(env.len(), 3);
+        assert!(env.contains(&mapping([("name", Value::from("PORT")), ("value", Value::from("8080"))])));
+        assert!(env.contains(&mapping([("name", Value::from("DEBUG")), ("value", Value::from("true"))])));
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, Result};
+use crate::source::SourceRepository;
+
+pub(crate) const CONFIG_PATH: &str = "/etc/synkronized/config.yaml";
+
+/// Server level configuration, read from the YAML file at `SYNKRONIZED_CONFIG`
+/// (or `CONFIG_PATH` when unset). Secrets stay in environment variables.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ServerConfig {
+    pub(crate) webhooks: WebhookConfig,
+    pub(crate) registries: RegistryConfig,
+    pub(crate) sources: SourceConfig,
+    pub(crate) defaults: DefaultsConfig,
+    pub(crate) policy: PolicyConfig,
+    pub(crate) access: AccessConfig,
+    pub(crate) ownership: OwnershipConfig,
+    pub(crate) apply: ApplyConfig,
+    pub(crate) rollout: RolloutConfig,
+    pub(crate) drift: DriftConfig,
+    pub(crate) operator: OperatorConfig,
+    pub(crate) leader_election: LeaderElectionConfig,
+    pub(crate) shutdown: ShutdownConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WebhookConfig {
+    /// How many `X-GitHub-Delivery` IDs are remembered for deduplication
+    pub(crate) delivery_cache_size: usize,
+    /// Reject payloads whose `updated_at` is older than this many seconds
+    pub(crate) max_age_seconds: Option<i64>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            delivery_cache_size: 1024,
+            max_age_seconds: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RegistryConfig {
+    /// Repository (`owner/name`, `gitlab:group/project` or `gitea:owner/name`) holding the
+    /// synkronized.yaml for images from registries that don't link one, keyed by image
+    /// repository, e.g. `docker.io/vaughnw128/synkronized`
+    pub(crate) repositories: HashMap<String, SourceRepository>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SourceConfig {
+    pub(crate) gitlab: GitLabConfig,
+    pub(crate) gitea: GiteaConfig,
+    /// Where to look for synkronized.yaml when it can't be fetched at the image's commit
+    pub(crate) ref_fallback: RefFallback,
+    /// Path of the config file in each repository
+    pub(crate) config_path: String,
+    /// Directory of per-app config files, used instead of `config_path` when present
+    pub(crate) config_directory: String,
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        SourceConfig {
+            gitlab: GitLabConfig::default(),
+            gitea: GiteaConfig::default(),
+            ref_fallback: RefFallback::default(),
+            config_path: "synkronized.yaml".to_string(),
+            config_directory: ".synkronized".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RefFallback {
+    /// Only the exact commit when one is known, failing the deployment if it has no file
+    None,
+    /// The commit, then the branch or tag the image was built from
+    Branch,
+    /// The commit, the image's branch or tag, then the repository's default branch
+    #[default]
+    DefaultBranch,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GitLabConfig {
+    /// Base URL of the GitLab instance, GitLab support is disabled when unset
+    pub(crate) url: Option<String>,
+    /// Host of the GitLab container registry
+    pub(crate) registry: String,
+}
+
+impl Default for GitLabConfig {
+    fn default() -> Self {
+        GitLabConfig {
+            url: None,
+            registry: "registry.gitlab.com".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct GiteaConfig {
+    /// Base URL of the Gitea instance, Gitea support is disabled when unset
+    pub(crate) url: Option<String>,
+}
+
+impl GiteaConfig {
+    /// Gitea serves its container registry from the same host as the web UI
+    pub(crate) fn registry_host(&self) -> Option<String> {
+        let url = reqwest::Url::parse(self.url.as_ref()?).ok()?;
+        match url.port() {
+            Some(port) => Some(format!("{}:{}", url.host_str()?, port)),
+            None => url.host_str().map(str::to_string)
+        }
+    }
+}
+
+/// Values layered beneath every app's `config`, from least to most specific: `values`, then the
+/// app's template, then the owner of its repository. The app's own `config` overrides them all.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DefaultsConfig {
+    pub(crate) values: Option<serde_yaml::Value>,
+    /// Keyed by synkronized-charts template, e.g. `single-container`
+    pub(crate) templates: HashMap<String, serde_yaml::Value>,
+    /// Keyed by repository owner (user, organization or top-level group)
+    pub(crate) owners: HashMap<String, serde_yaml::Value>,
+}
+
+impl DefaultsConfig {
+    /// The defaults applying to an app, named after where they are configured, in merge order
+    pub(crate) fn layers(&self, template: Option<&str>, owner: &str) -> Vec<(String, serde_yaml::Value)> {
+        let template = template.and_then(|t| Some((format!("defaults.templates.{}", t), self.templates.get(t)?.clone())));
+        let owner = self.owners.get(owner).map(|v| (format!("defaults.owners.{}", owner), v.clone()));
+        let values = self.values.clone().map(|v| ("defaults.values".to_string(), v));
+
+        [values, template, owner].into_iter().flatten().collect()
+    }
+}
+
+/// Restrictions on what apps may deploy, checked before anything is applied. Empty lists
+/// leave the corresponding check disabled.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PolicyConfig {
+    /// Hostnames apps of each repository owner may use for `gateway.hostname`,
+    /// `*.example.com` allowing any subdomain of `example.com`
+    pub(crate) hostnames: HashMap<String, Vec<String>>,
+    /// Allowed values of `size`
+    pub(crate) sizes: Vec<String>,
+    /// Registry hosts images may be pulled from, e.g. `ghcr.io`
+    pub(crate) registries: Vec<String>,
+    /// Registry namespaces images may belong to, e.g. `vaughnw128` for `ghcr.io/vaughnw128/app`
+    pub(crate) image_owners: Vec<String>,
+    /// Namespaces no app may deploy to
+    pub(crate) forbidden_namespaces: Vec<String>,
+}
+
+/// Which GitHub webhook events may deploy at all. Any match in `deny` refuses the event; when
+/// `allow` lists anything, the event must also match one of its entries.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AccessConfig {
+    pub(crate) allow: AccessList,
+    pub(crate) deny: AccessList,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AccessList {
+    /// Full names, e.g. `vaughnw128/synkronized`
+    pub(crate) repositories: Vec<String>,
+    /// Logins of repository owners
+    pub(crate) owners: Vec<String>,
+    /// Logins of the organization the event came from
+    pub(crate) organizations: Vec<String>,
+    /// Logins of the user who triggered the event
+    pub(crate) senders: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OwnershipConfig {
+    /// Applications that may be taken over by another repository than the one that created
+    /// them, keyed by Application name
+    pub(crate) transfers: HashMap<String, SourceRepository>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ApplyConfig {
+    /// Take over fields set by other field managers, e.g. edits made in the Argo UI, instead
+    /// of failing the deployment with the conflicting fields
+    pub(crate) force: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RolloutConfig {
+    /// Watch applied Applications until Argo reports them synced and healthy, and only then
+    /// report the deployment as successful
+    pub(crate) wait: bool,
+    /// How long to wait before reporting the rollout as timed out
+    pub(crate) timeout_seconds: u64,
+    pub(crate) sync: SyncConfig,
+}
+
+impl Default for RolloutConfig {
+    fn default() -> Self {
+        RolloutConfig {
+            wait: false,
+            timeout_seconds: 300,
+            sync: SyncConfig::default(),
+        }
+    }
+}
+
+/// Starting a sync right after each apply, so Applications without automated sync still roll out
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SyncConfig {
+    pub(crate) trigger: SyncTrigger,
+    /// Delete resources that are no longer part of the app
+    pub(crate) prune: bool,
+    /// Sync options of the operation, e.g. `ApplyOutOfSyncOnly=true`
+    pub(crate) options: Vec<String>,
+    /// Base URL of the Argo CD API for the `api` trigger, authenticated with `ARGOCD_API_TOKEN`
+    pub(crate) argocd_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncTrigger {
+    /// Leave syncing to Argo
+    #[default]
+    None,
+    /// Set the Application's `operation` field
+    Operation,
+    /// Call the Argo CD API's sync endpoint
+    Api,
+}
+
+/// Periodic comparison of every managed Application with what its repository declares
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DriftConfig {
+    /// Seconds between checks, drift detection is disabled when unset
+    pub(crate) interval_seconds: Option<u64>,
+    /// Re-apply drifted Applications, taking back fields changed by hand
+    pub(crate) reapply: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OperatorConfig {
+    /// Reconcile SynkronizedApp resources into Applications, with publishes of their package
+    /// updating the resource's image instead of being deployed from the repository's config
+    pub(crate) enabled: bool,
+}
+
+/// Running several replicas. Every replica accepts webhooks and queues the deployments, while
+/// only the replica holding the Lease deploys them and runs the background loops.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LeaderElectionConfig {
+    pub(crate) enabled: bool,
+    /// Namespace of the Lease and the queued jobs, including those queued on shutdown
+    pub(crate) namespace: String,
+    pub(crate) lease_name: String,
+    /// How long a leader keeps the Lease without renewing it
+    pub(crate) lease_duration_seconds: u64,
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        LeaderElectionConfig {
+            enabled: false,
+            namespace: "synkronized".to_string(),
+            lease_name: "synkronized".to_string(),
+            lease_duration_seconds: 15,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ShutdownConfig {
+    /// How long in-flight deployments may take to finish after SIGTERM, before the unfinished
+    /// ones are queued to resume on restart
+    pub(crate) drain_seconds: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_seconds: 25,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub(crate) fn load() -> Result<ServerConfig> {
+        let path = std::env::var("SYNKRONIZED_CONFIG").unwrap_or(CONFIG_PATH.to_string());
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let config: ServerConfig = serde_yaml::from_str(&contents)?;
+                config.validate()?;
+                Ok(config)
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("No server config found at {}, using defaults", path);
+                Ok(ServerConfig::default())
+            },
+            Err(e) => Err(e.into())
+        }
+    }
+
+    /// Rejects settings that would only fail once the server is running
+    fn validate(&self) -> Result<()> {
+        if self.drift.interval_seconds == Some(0) {
+            return Err(anyhow!("drift.intervalSeconds must be greater than 0"));
+        }
+        if self.leader_election.lease_duration_seconds == 0 {
+            return Err(anyhow!("leaderElection.leaseDurationSeconds must be greater than 0"));
+        }
+        Ok(())
+    }
+}
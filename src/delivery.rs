@@ -0,0 +1,131 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Bounded record of webhook deliveries that have already been handled, along with the ones
+/// still being processed. Once full, the oldest handled delivery IDs are evicted first.
+pub struct DeliveryLog {
+    capacity: usize,
+    order: VecDeque<String>,
+    seen: HashSet<String>,
+    in_progress: HashSet<String>,
+}
+
+/// Why a delivery is not processed again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seen {
+    /// An earlier attempt is still running and may yet fail
+    InProgress,
+    Handled,
+}
+
+impl DeliveryLog {
+    pub fn new(capacity: usize) -> DeliveryLog {
+        DeliveryLog {
+            capacity: capacity.max(1),
+            order: VecDeque::new(),
+            seen: HashSet::new(),
+            in_progress: HashSet::new(),
+        }
+    }
+
+    /// Records a handled delivery ID.
+    fn record(&mut self, delivery: &str) {
+        if self.seen.contains(delivery) {
+            return;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(delivery.to_string());
+        self.seen.insert(delivery.to_string());
+    }
+}
+
+/// Starts processing a delivery, unless it has been handled or is being handled already.
+pub fn start<'a>(log: &'a Mutex<DeliveryLog>, delivery: &str) -> Result<Processing<'a>, Seen> {
+    let mut deliveries = log.lock().unwrap();
+    if deliveries.seen.contains(delivery) {
+        return Err(Seen::Handled);
+    }
+    if !deliveries.in_progress.insert(delivery.to_string()) {
+        return Err(Seen::InProgress);
+    }
+
+    Ok(Processing { log, delivery: delivery.to_string(), handled: false })
+}
+
+/// A delivery being processed. Unless marked handled, it is forgotten when dropped, so that a
+/// redelivery of a failed or cancelled attempt is processed again.
+pub struct Processing<'a> {
+    log: &'a Mutex<DeliveryLog>,
+    delivery: String,
+    handled: bool,
+}
+
+impl Processing<'_> {
+    pub fn handled(mut self) {
+        self.handled = true;
+    }
+}
+
+impl Drop for Processing<'_> {
+    fn drop(&mut self) {
+        let mut deliveries = self.log.lock().unwrap();
+        deliveries.in_progress.remove(&self.delivery);
+        if self.handled {
+            deliveries.record(&self.delivery);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handled_deliveries_are_seen() {
+        let log = Mutex::new(DeliveryLog::new(4));
+        start(&log, "a").unwrap().handled();
+        assert_eq!(start(&log, "a").err(), Some(Seen::Handled));
+    }
+
+    #[test]
+    fn running_deliveries_are_in_progress() {
+        let log = Mutex::new(DeliveryLog::new(4));
+        let processing = start(&log, "a").unwrap();
+        assert_eq!(start(&log, "a").err(), Some(Seen::InProgress));
+        processing.handled();
+        assert_eq!(start(&log, "a").err(), Some(Seen::Handled));
+    }
+
+    #[test]
+    fn unhandled_deliveries_are_forgotten() {
+        let log = Mutex::new(DeliveryLog::new(4));
+        drop(start(&log, "a").unwrap());
+        assert!(start(&log, "a").is_ok());
+    }
+
+    #[test]
+    fn oldest_deliveries_are_evicted_at_capacity() {
+        let log = Mutex::new(DeliveryLog::new(2));
+        for delivery in ["a", "b", "c"] {
+            start(&log, delivery).unwrap().handled();
+        }
+        assert!(start(&log, "a").is_ok());
+        assert_eq!(start(&log, "b").err(), Some(Seen::Handled));
+        assert_eq!(start(&log, "c").err(), Some(Seen::Handled));
+    }
+
+    #[test]
+    fn in_progress_deliveries_are_never_evicted() {
+        let log = Mutex::new(DeliveryLog::new(1));
+        let _processing = start(&log, "a").unwrap();
+        start(&log, "b").unwrap().handled();
+        start(&log, "c").unwrap().handled();
+        assert_eq!(start(&log, "a").err(), Some(Seen::InProgress));
+    }
+}
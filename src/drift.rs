@@ -0,0 +1,103 @@
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use kube::ResourceExt;
+use serde::Serialize;
+use crate::{argo, bound_projects, render_project, AppState};
+use crate::policy::PolicyViolation;
+use crate::utils::{diff_json, Change};
+
+/// The last drift check of an app.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DriftReport {
+    pub(crate) checked_at: DateTime<Utc>,
+    /// Fields of the live spec that differ from the rendered one
+    pub(crate) changes: Vec<Change>,
+    /// Why the app couldn't be checked
+    pub(crate) error: Option<String>,
+    pub(crate) reapplied: bool,
+}
+
+impl DriftReport {
+    pub fn drifted(&self) -> bool {
+        !self.changes.is_empty()
+    }
+}
+
+/// Renders an Application again from the publish it was last deployed for, returning it with
+/// the fields of the live spec that differ. The config is read at the revision that built the
+/// image, as it was for the deployment, so drift means the Application was changed outside of
+/// synkronized; config merged since then is deployed by the next publish, never with an older image.
+async fn check(state: &AppState, live: &argo::Application) -> Result<(argo::Application, Vec<Change>)> {
+    let name = live.name_any();
+    let image = live.published()?
+        .ok_or(anyhow!("{} has no recorded publish to render it from", name))?;
+    let repo = image.repo.clone()
+        .ok_or(anyhow!("{} was deployed without a repository", name))?;
+    let source = state.sources.get(repo.provider)?;
+
+    let mut project = bound_projects(&image, &repo, source, &state.config)
+        .await?
+        .into_iter()
+        .find(|project| project.synkronized.name == name)
+        .ok_or(anyhow!("{} is no longer declared by {}", name, repo))?;
+    // Newer chart releases are picked up by the next publish, not reported as drift
+    project.chart_version = live.chart_version().map(str::to_string);
+    let rendered = render_project(project, &image, &repo, source, &state.config, &state.kube_client).await?;
+    if !rendered.violations.is_empty() {
+        return Err(PolicyViolation { app: name, reasons: rendered.violations }.into());
+    }
+
+    // Both sides go through the typed Application, so fields synkronized doesn't manage are ignored
+    let (desired, live) = (serde_json::to_value(&rendered.application)?, serde_json::to_value(live)?);
+    let mut changes = vec![];
+    diff_json("spec", live.pointer("/spec"), desired.pointer("/spec"), &mut changes);
+
+    Ok((rendered.application, changes))
+}
+
+/// Checks every managed Application for drift each `interval`, re-applying drifted ones when configured.
+pub async fn reconcile(state: Arc<AppState>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+
+        let applications = match argo::Application::managed(&state.kube_client).await {
+            Ok(applications) => applications,
+            Err(e) => {
+                tracing::warn!("Unable to list Applications for drift detection: {}", e);
+                continue;
+            }
+        };
+
+        // SynkronizedApps are rendered from the cluster, and their controller keeps them in line
+        for live in applications.into_iter().filter(|app| app.synkronized_app().is_none()) {
+            let name = live.name_any();
+            let mut report = DriftReport { checked_at: Utc::now(), changes: vec![], error: None, reapplied: false };
+
+            match check(&state, &live).await {
+                Ok((desired, changes)) => {
+                    report.changes = changes;
+                    if report.drifted() {
+                        tracing::warn!("{} has drifted from its config in {} fields", name, report.changes.len());
+                    }
+                    if report.drifted() && state.config.drift.reapply {
+                        // Drift means someone else set the fields, so they are taken back
+                        match desired.apply(&state.kube_client, true).await {
+                            Ok(_) => report.reapplied = true,
+                            Err(e) => report.error = Some(format!("Unable to re-apply: {}", e)),
+                        }
+                    }
+                },
+                Err(e) => {
+                    tracing::warn!("Unable to check {} for drift: {}", name, e);
+                    report.error = Some(e.to_string());
+                }
+            }
+
+            state.drift.lock().unwrap().insert(name, report);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Request};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use hmac_sha256::HMAC;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use subtle::ConstantTimeEq;
+use crate::{json_error, PublishedImage};
+use crate::config::ServerConfig;
+use crate::registry::RegistryWebhook;
+use crate::source::{Provider, SourceRepository};
+
+/// Gitea `package` webhook. Deliveries are signed like GitHub's, but `X-Gitea-Signature`
+/// carries the bare hex HMAC-SHA256 of the body keyed with `GITEA_WEBHOOK_TOKEN`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageEvent {
+    pub(crate) action: String,
+    pub(crate) package: Package,
+    pub(crate) sender: Option<User>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Package {
+    pub(crate) id: i64,
+    pub(crate) owner: User,
+    pub(crate) repository: Option<Repository>,
+    pub(crate) creator: Option<User>,
+    #[serde(rename = "type")]
+    pub(crate) package_type: String,
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) html_url: Option<String>,
+    pub(crate) created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub(crate) id: i64,
+    pub(crate) login: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Repository {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) full_name: String,
+    pub(crate) default_branch: Option<String>,
+}
+
+impl<S> FromRequest<S> for PackageEvent
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let webhook_token = std::env::var("GITEA_WEBHOOK_TOKEN")
+            .map_err(|_| json_error("GITEA_WEBHOOK_TOKEN is not configured, refusing webhook."))?;
+
+        let event = req
+            .headers()
+            .get("X-Gitea-Event")
+            .and_then(|v| v.to_str().ok())
+            .ok_or(json_error("Expected X-Gitea-Event"))?;
+        if event != "package" {
+            return Err(json_error(format!("Unsupported Gitea event: {}", event)));
+        }
+
+        let signature = req
+            .headers()
+            .get("X-Gitea-Signature")
+            .and_then(|v| v.to_str().ok())
+            .ok_or(json_error("Signature is missing."))?;
+        let signature = hex::decode(signature).map_err(|_| json_error("Malformed signature."))?;
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|_| json_error("Error reading request body."))?;
+        let mac = HMAC::mac(&body, webhook_token.as_bytes());
+        if mac.ct_ne(&signature).into() {
+            return Err(json_error("Bad signature."));
+        }
+
+        let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+        serde_path_to_error::deserialize(deserializer)
+            .map_err(|e| json_error(format!("Unable to parse webhook request body: {}", e)))
+    }
+}
+
+impl RegistryWebhook for PackageEvent {
+    const REGISTRY: &'static str = "gitea";
+
+    fn images(self, config: &ServerConfig) -> Vec<PublishedImage> {
+        let package = self.package;
+
+        // Each manifest of a multi-arch push is also announced under its digest as the version
+        if self.action != "created" || package.package_type != "container" || package.version.starts_with("sha256:") {
+            return vec![];
+        }
+        let Some(registry) = config.sources.gitea.registry_host() else {
+            return vec![];
+        };
+
+        vec![PublishedImage {
+            url: format!("{}/{}/{}:{}", registry, package.owner.login.to_lowercase(), package.name, package.version),
+            repo: package.repository.map(|r| SourceRepository { provider: Provider::Gitea, name: r.full_name }),
+            commit: None,
+            git_ref: None,
+            name: package.name,
+            owner: package.owner.login,
+            tag: Some(package.version),
+            digest: None,
+            updated_at: package.created_at,
+        }]
+    }
+}
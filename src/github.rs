@@ -2,17 +2,75 @@ use std::collections::HashMap;
 use axum::body::Bytes;
 use axum::extract::{FromRequest, Request};
 use axum::Json;
+use chrono::{DateTime, Utc};
 use hmac_sha256::HMAC;
 use http::StatusCode;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use subtle::ConstantTimeEq;
-use crate::json_error;
+use crate::{json_error, PublishedImage};
+use crate::policy::EventOrigin;
+use crate::source::SourceRepository;
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "action", rename_all = "snake_case")]
+const GHCR: &str = "ghcr.io";
+
+/// Webhook events that synkronized acts on, keyed by the `X-GitHub-Event` header and the
+/// payload's `action`.
+#[derive(Debug)]
 pub enum WebhookPayload {
-    Published(RegistryPublished)
+    Ping,
+    RegistryPackagePublished(Box<RegistryPublished>),
+    PackagePublished(Box<PackageEvent>),
+    Unsupported {
+        event: String,
+        action: Option<String>,
+    }
+}
+
+/// Just enough of a payload to read its `action` before choosing the full type to parse.
+#[derive(Deserialize)]
+struct WebhookAction {
+    action: Option<String>,
+}
+
+impl WebhookPayload {
+    fn parse(event: &str, body: &[u8]) -> Result<WebhookPayload, (StatusCode, Json<Value>)> {
+        let action = serde_json::from_slice::<WebhookAction>(body)
+            .map_err(|e| json_error(format!("Unable to parse webhook request body: {}", e)))?
+            .action;
+
+        let payload = match (event, action.as_deref()) {
+            ("ping", _) => WebhookPayload::Ping,
+            ("registry_package", Some("published")) => WebhookPayload::RegistryPackagePublished(parse_body(body)?),
+            ("package", Some("published" | "updated")) => WebhookPayload::PackagePublished(parse_body(body)?),
+            _ => WebhookPayload::Unsupported { event: event.to_string(), action }
+        };
+
+        Ok(payload)
+    }
+
+    /// The repository, owner, organization and sender of events that deploy
+    pub fn origin(&self) -> Option<EventOrigin> {
+        let (repository, organization, sender) = match self {
+            WebhookPayload::RegistryPackagePublished(payload) => (&payload.repository, &payload.organization, &payload.sender),
+            WebhookPayload::PackagePublished(payload) => (&payload.repository, &payload.organization, &payload.sender),
+            WebhookPayload::Ping | WebhookPayload::Unsupported { .. } => return None,
+        };
+
+        Some(EventOrigin {
+            repository: repository.as_ref().map(|r| r.full_name.clone()),
+            owner: repository.as_ref().map(|r| r.owner.login.clone()),
+            organization: organization.as_ref().map(|o| o.login.clone()),
+            sender: Some(sender.login.clone()),
+        })
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, (StatusCode, Json<Value>)> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(body);
+    serde_path_to_error::deserialize(deserializer)
+        .map_err(|e| json_error(format!("Unable to parse webhook request body: {}", e)))
 }
 
 impl<S> FromRequest<S> for WebhookPayload
@@ -26,6 +84,12 @@ where
         // TODO: Do this more properly with some type of environment variable handler eg https://nrempel.com/handling-environment-variables-with-axum/
         let webhook_token = std::env::var("GITHUB_WEBHOOK_TOKEN").expect("GITHUB_WEBHOOK_TOKEN environment variable is required");
 
+        let event = req
+            .headers()
+            .get("X-GitHub-Event")
+            .and_then(|v| v.to_str().ok())
+            .ok_or(json_error("Expected X-GitHub-Event"))?
+            .to_string();
         let signature_sha256 = req
             .headers()
             .get("X-Hub-Signature-256")
@@ -42,12 +106,7 @@ where
             return Err(json_error("Bad signature."));
         }
 
-        // TODO: Do this more properly with Json<T>
-        let payload = serde_json::from_slice::<WebhookPayload>(&body)
-            .map_err(|e| json_error(format!("Unable to parse webhook request body: {}", e)))?;
-
-        Ok(payload)
-
+        WebhookPayload::parse(&event, &body)
     }
 }
 
@@ -80,6 +139,119 @@ pub struct RegistryPublished {
     pub(crate) sender: SenderClass,
 }
 
+impl From<RegistryPublished> for PublishedImage {
+    fn from(payload: RegistryPublished) -> Self {
+        let package = payload.registry_package;
+        let version = package.package_version;
+        let tag = version.container_metadata.and_then(|m| m.tag);
+
+        PublishedImage {
+            updated_at: parse_timestamp(version.updated_at.as_ref().unwrap_or(&package.updated_at)),
+            repo: payload.repository.map(|r| SourceRepository::github(r.full_name)),
+            commit: version.target_oid,
+            git_ref: version.target_commitish,
+            owner: package.owner.login,
+            tag: tag.as_ref().and_then(|t| t.name.clone()).filter(|t| !t.is_empty()),
+            digest: tag.and_then(|t| t.digest),
+            url: version.package_url,
+            name: package.name,
+        }
+    }
+}
+
+/// Payload of the `package` event, which GitHub sends alongside (and eventually instead of)
+/// `registry_package` when a package is published or updated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageEvent {
+    pub(crate) enterprise: Option<Enterprise>,
+    pub(crate) installation: Option<SimpleInstallation>,
+    pub(crate) organization: Option<OrganizationSimple>,
+    pub(crate) package: Package,
+    pub(crate) repository: Option<Repository>,
+    /// A GitHub user.
+    pub(crate) sender: SenderClass,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Package {
+    pub(crate) created_at: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) ecosystem: String,
+    pub(crate) html_url: String,
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) namespace: String,
+    pub(crate) owner: Option<RegistryPackageOwner>,
+    pub(crate) package_type: String,
+    pub(crate) package_version: Option<PackageEventVersion>,
+    pub(crate) registry: Option<Registry>,
+    pub(crate) updated_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageEventVersion {
+    pub(crate) author: Option<PackageVersionAuthor>,
+    pub(crate) container_metadata: Option<ContainerMetadata>,
+    pub(crate) created_at: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) html_url: String,
+    pub(crate) id: i64,
+    pub(crate) installation_command: Option<String>,
+    pub(crate) name: String,
+    pub(crate) package_url: Option<String>,
+    pub(crate) summary: Option<String>,
+    pub(crate) tag_name: Option<String>,
+    pub(crate) target_commitish: Option<String>,
+    pub(crate) target_oid: Option<String>,
+    pub(crate) updated_at: Option<String>,
+    pub(crate) version: String,
+}
+
+impl From<PackageEvent> for PublishedImage {
+    fn from(payload: PackageEvent) -> Self {
+        let package = payload.package;
+        let version = package.package_version;
+        let tag = version.as_ref()
+            .and_then(|v| v.container_metadata.as_ref())
+            .and_then(|m| m.tag.clone());
+        let tag_name = tag.as_ref().and_then(|t| t.name.clone()).filter(|t| !t.is_empty());
+        let digest = tag.and_then(|t| t.digest);
+
+        // Some deliveries of this event omit `package_url`, so fall back to the ghcr.io layout
+        let url = version.as_ref()
+            .and_then(|v| v.package_url.clone())
+            .unwrap_or_else(|| match (&tag_name, &digest) {
+                (Some(tag), _) => format!("{GHCR}/{}/{}:{}", package.namespace, package.name, tag),
+                (None, Some(digest)) => format!("{GHCR}/{}/{}@{}", package.namespace, package.name, digest),
+                (None, None) => format!("{GHCR}/{}/{}:", package.namespace, package.name),
+            });
+        let updated_at = version.as_ref()
+            .and_then(|v| v.updated_at.as_ref())
+            .or(package.updated_at.as_ref())
+            .and_then(|t| parse_timestamp(t));
+        let commit = version.as_ref().and_then(|v| v.target_oid.clone());
+        let git_ref = version.and_then(|v| v.target_commitish);
+
+        PublishedImage {
+            owner: package.owner.map(|o| o.login).unwrap_or(package.namespace),
+            repo: payload.repository.map(|r| SourceRepository::github(r.full_name)),
+            commit,
+            git_ref,
+            name: package.name,
+            tag: tag_name,
+            digest,
+            url,
+            updated_at,
+        }
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
 /// An enterprise on GitHub. Webhook payloads contain the `enterprise` property when the
 /// webhook is configured
 /// on an enterprise account or an organization that's part of an enterprise account. For
@@ -509,7 +681,7 @@ pub struct Repository {
     ///
     /// - `PR_TITLE` - default to the pull request's title.
     /// - `MERGE_MESSAGE` - default to the classic title for a merge message (e.g., Merge pull
-    /// request #123 from branch-name).
+    ///   request #123 from branch-name).
     pub(crate) merge_commit_title: Option<MergeCommitTitle>,
     pub(crate) merges_url: String,
     pub(crate) milestones_url: String,
@@ -544,7 +716,7 @@ pub struct Repository {
     ///
     /// - `PR_TITLE` - default to the pull request's title.
     /// - `COMMIT_OR_PR_TITLE` - default to the commit's title (if only one commit) or the pull
-    /// request's title (when more than one commit).
+    ///   request's title (when more than one commit).
     pub(crate) squash_merge_commit_title: Option<SquashMergeCommitTitle>,
     pub(crate) ssh_url: String,
     pub(crate) stargazers_count: i64,
@@ -604,7 +776,7 @@ pub enum MergeCommitMessage {
 ///
 /// - `PR_TITLE` - default to the pull request's title.
 /// - `MERGE_MESSAGE` - default to the classic title for a merge message (e.g., Merge pull
-/// request #123 from branch-name).
+///   request #123 from branch-name).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum MergeCommitTitle {
@@ -698,7 +870,7 @@ pub enum SquashMergeCommitMessage {
 ///
 /// - `PR_TITLE` - default to the pull request's title.
 /// - `COMMIT_OR_PR_TITLE` - default to the commit's title (if only one commit) or the pull
-/// request's title (when more than one commit).
+///   request's title (when more than one commit).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum SquashMergeCommitTitle {
@@ -770,7 +942,7 @@ pub struct TemplateRepository {
     ///
     /// - `PR_TITLE` - default to the pull request's title.
     /// - `MERGE_MESSAGE` - default to the classic title for a merge message (e.g., Merge pull
-    /// request #123 from branch-name).
+    ///   request #123 from branch-name).
     pub(crate) merge_commit_title: Option<MergeCommitTitle>,
     pub(crate) merges_url: Option<String>,
     pub(crate) milestones_url: Option<String>,
@@ -797,7 +969,7 @@ pub struct TemplateRepository {
     ///
     /// - `PR_TITLE` - default to the pull request's title.
     /// - `COMMIT_OR_PR_TITLE` - default to the commit's title (if only one commit) or the pull
-    /// request's title (when more than one commit).
+    ///   request's title (when more than one commit).
     pub(crate) squash_merge_commit_title: Option<SquashMergeCommitTitle>,
     pub(crate) ssh_url: Option<String>,
     pub(crate) stargazers_count: Option<i64>,
@@ -877,4 +1049,111 @@ pub struct SenderClass {
     pub(crate) simple_user_type: String,
     pub(crate) url: String,
     pub(crate) user_view_type: Option<String>,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A user as GitHub embeds it in payloads, as owner, author or sender
+    fn user(login: &str) -> Value {
+        let url = format!("https://api.github.com/users/{}", login);
+        json!({
+            "login": login, "id": 1, "node_id": "U_1", "type": "User", "site_admin": false,
+            "avatar_url": "", "gravatar_id": "", "html_url": format!("https://github.com/{}", login), "url": url,
+            "events_url": url, "followers_url": url, "following_url": url, "gists_url": url, "organizations_url": url,
+            "received_events_url": url, "repos_url": url, "starred_url": url, "subscriptions_url": url,
+        })
+    }
+
+    fn body(payload: Value) -> Vec<u8> {
+        serde_json::to_vec(&payload).unwrap()
+    }
+
+    fn registry_package(action: &str) -> Vec<u8> {
+        body(json!({
+            "action": action,
+            "sender": user("vaughnw128"),
+            "registry_package": {
+                "id": 1, "name": "synkronized", "namespace": "vaughnw128", "description": "", "ecosystem": "CONTAINER",
+                "package_type": "CONTAINER", "html_url": "", "created_at": "2025-01-01T00:00:00Z", "updated_at": "2025-01-01T00:00:00Z",
+                "owner": user("vaughnw128"),
+                "registry": {},
+                "package_version": {
+                    "id": 2, "name": "sha256:abc", "version": "sha256:abc", "description": "", "summary": "", "html_url": "",
+                    "installation_command": "", "metadata": [], "package_files": [],
+                    "package_url": "ghcr.io/vaughnw128/synkronized:latest",
+                    "target_oid": "0123abc",
+                    "container_metadata": {"tag": {"name": "latest", "digest": "sha256:abc"}},
+                },
+            },
+        }))
+    }
+
+    fn package(action: &str) -> Vec<u8> {
+        body(json!({
+            "action": action,
+            "sender": user("vaughnw128"),
+            "package": {
+                "id": 1, "name": "synkronized", "namespace": "vaughnw128", "ecosystem": "CONTAINER",
+                "package_type": "CONTAINER", "html_url": "",
+                "package_version": {
+                    "id": 2, "name": "sha256:abc", "version": "sha256:abc", "html_url": "",
+                    "container_metadata": {"tag": {"name": "v1.2.0", "digest": "sha256:abc"}},
+                },
+            },
+        }))
+    }
+
+    #[test]
+    fn ping() {
+        let payload = WebhookPayload::parse("ping", &body(json!({"zen": "Keep it logically awesome.", "hook_id": 1}))).unwrap();
+        assert!(matches!(payload, WebhookPayload::Ping));
+    }
+
+    #[test]
+    fn registry_package_published() {
+        let payload = WebhookPayload::parse("registry_package", &registry_package("published")).unwrap();
+        let WebhookPayload::RegistryPackagePublished(payload) = payload else {
+            panic!("expected a registry_package publish, got {:?}", payload);
+        };
+        let image = PublishedImage::from(*payload);
+        assert_eq!(image.url, "ghcr.io/vaughnw128/synkronized:latest");
+        assert_eq!(image.tag.as_deref(), Some("latest"));
+        assert_eq!(image.commit.as_deref(), Some("0123abc"));
+    }
+
+    #[test]
+    fn package_updated() {
+        let payload = WebhookPayload::parse("package", &package("updated")).unwrap();
+        let WebhookPayload::PackagePublished(payload) = payload else {
+            panic!("expected a package publish, got {:?}", payload);
+        };
+        // Without a package_url the ghcr.io layout is assumed
+        assert_eq!(PublishedImage::from(*payload).url, "ghcr.io/vaughnw128/synkronized:v1.2.0");
+    }
+
+    #[test]
+    fn package_published() {
+        let payload = WebhookPayload::parse("package", &package("published")).unwrap();
+        assert!(matches!(payload, WebhookPayload::PackagePublished(_)));
+    }
+
+    #[test]
+    fn other_actions_are_unsupported() {
+        let payload = WebhookPayload::parse("registry_package", &body(json!({"action": "updated"}))).unwrap();
+        assert!(matches!(payload, WebhookPayload::Unsupported { action: Some(action), .. } if action == "updated"));
+    }
+
+    #[test]
+    fn other_events_are_unsupported() {
+        let payload = WebhookPayload::parse("release", &body(json!({"action": "published", "release": {"tag_name": "v1.2.0"}}))).unwrap();
+        assert!(matches!(payload, WebhookPayload::Unsupported { event, action: Some(action) } if event == "release" && action == "published"));
+    }
+
+    #[test]
+    fn malformed_publishes_are_rejected() {
+        let (status, _) = WebhookPayload::parse("registry_package", &body(json!({"action": "published"}))).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
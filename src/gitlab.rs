@@ -0,0 +1,95 @@
+use axum::extract::{FromRequest, Request};
+use axum::Json;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::{json_error, PublishedImage};
+use crate::config::ServerConfig;
+use crate::registry::{read_body, verify_token, RegistryWebhook};
+use crate::source::{Provider, SourceRepository};
+
+/// GitLab has no registry push webhook, so a successful pipeline on a tag is taken to mean
+/// the project's container image was published under that tag. GitLab sends the webhook's
+/// secret token verbatim in `X-Gitlab-Token`, matched against `GITLAB_WEBHOOK_TOKEN`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineEvent {
+    pub(crate) object_kind: String,
+    pub(crate) object_attributes: PipelineAttributes,
+    pub(crate) project: Project,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipelineAttributes {
+    pub(crate) id: i64,
+    #[serde(rename = "ref")]
+    pub(crate) git_ref: String,
+    pub(crate) tag: bool,
+    pub(crate) sha: String,
+    pub(crate) status: String,
+    pub(crate) finished_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Project {
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) namespace: String,
+    pub(crate) path_with_namespace: String,
+    pub(crate) web_url: String,
+}
+
+impl<S> FromRequest<S> for PipelineEvent
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let token = req
+            .headers()
+            .get("X-Gitlab-Token")
+            .and_then(|v| v.to_str().ok());
+        verify_token(token, "GITLAB_WEBHOOK_TOKEN")?;
+
+        let event = req
+            .headers()
+            .get("X-Gitlab-Event")
+            .and_then(|v| v.to_str().ok())
+            .ok_or(json_error("Expected X-Gitlab-Event"))?;
+        if event != "Pipeline Hook" {
+            return Err(json_error(format!("Unsupported GitLab event: {}", event)));
+        }
+
+        read_body(req, state).await
+    }
+}
+
+impl RegistryWebhook for PipelineEvent {
+    const REGISTRY: &'static str = "gitlab";
+
+    fn images(self, config: &ServerConfig) -> Vec<PublishedImage> {
+        let pipeline = self.object_attributes;
+        if !pipeline.tag || pipeline.status != "success" {
+            return vec![];
+        }
+
+        // GitLab reports `2016-08-12 15:26:29 UTC` rather than RFC 3339
+        let updated_at = pipeline.finished_at
+            .and_then(|t| NaiveDateTime::parse_from_str(&t, "%Y-%m-%d %H:%M:%S UTC").ok())
+            .map(|t| DateTime::<Utc>::from_naive_utc_and_offset(t, Utc));
+        let path = self.project.path_with_namespace.to_lowercase();
+
+        vec![PublishedImage {
+            name: self.project.name,
+            owner: self.project.namespace,
+            url: format!("{}/{}:{}", config.sources.gitlab.registry, path, pipeline.git_ref),
+            repo: Some(SourceRepository { provider: Provider::Gitlab, name: self.project.path_with_namespace }),
+            commit: Some(pipeline.sha),
+            git_ref: Some(pipeline.git_ref.clone()),
+            tag: Some(pipeline.git_ref),
+            digest: None,
+            updated_at,
+        }]
+    }
+}
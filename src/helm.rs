@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use serde_derive::{Deserialize, Serialize};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 pub(crate) const CHART_REPO: &str = "https://charts.vaughn.sh";
 
@@ -44,12 +44,12 @@ impl Template {
             .await?;
 
         let charts: Charts = serde_yaml::from_str(&body)?;
-        match charts.entries.get(chart_name) {
-            Some(charts) => Ok(Template {
-                name: charts[0].name.clone(),
-                version: charts[0].version.clone()
+        match charts.entries.get(chart_name).and_then(|charts| charts.first()) {
+            Some(chart) => Ok(Template {
+                name: chart.name.clone(),
+                version: chart.version.clone()
             }),
-            None => panic!("No viable charts were found for {}", chart_name)
+            None => Err(anyhow!("No viable charts were found for {}", chart_name))
         }
     }
 }
@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use chrono::Utc;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use kube::{Api, Client};
+use kube::api::PostParams;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use crate::config::LeaderElectionConfig;
+
+/// Name this replica holds the Lease under
+pub fn identity() -> String {
+    std::env::var("POD_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| format!("synkronized-{}", std::process::id()))
+}
+
+/// Keeps trying to acquire or renew the Lease, publishing whether this replica is the leader.
+pub async fn run(client: Client, config: LeaderElectionConfig, identity: String, leader: watch::Sender<bool>) {
+    let api: Api<Lease> = Api::namespaced(client, &config.namespace);
+    let duration = Duration::from_secs(config.lease_duration_seconds);
+    let mut ticks = tokio::time::interval(duration / 3);
+    let mut renewed: Option<Instant> = None;
+
+    loop {
+        ticks.tick().await;
+
+        let is_leader = match try_acquire(&api, &config.lease_name, &identity, duration).await {
+            Ok(true) => {
+                renewed = Some(Instant::now());
+                true
+            },
+            Ok(false) => false,
+            // The Lease is still ours until it expires, even if renewing it failed
+            Err(e) => {
+                tracing::warn!("Unable to renew Lease {}: {}", config.lease_name, e);
+                renewed.is_some_and(|t| t.elapsed() < duration)
+            }
+        };
+
+        leader.send_if_modified(|current| {
+            if *current == is_leader {
+                return false;
+            }
+            match is_leader {
+                true => tracing::info!("{} is now the leader", identity),
+                false => tracing::info!("{} is no longer the leader", identity),
+            }
+            *current = is_leader;
+            true
+        });
+    }
+}
+
+/// Creates, renews or takes over an expired Lease. Updates carry the Lease's resourceVersion,
+/// so when replicas race only one of them wins.
+async fn try_acquire(api: &Api<Lease>, name: &str, identity: &str, duration: Duration) -> Result<bool> {
+    let now = Utc::now();
+    let seconds = duration.as_secs() as i32;
+
+    let Some(mut lease) = api.get_opt(name).await? else {
+        let lease = Lease {
+            metadata: ObjectMeta { name: Some(name.to_string()), ..Default::default() },
+            spec: Some(LeaseSpec {
+                holder_identity: Some(identity.to_string()),
+                lease_duration_seconds: Some(seconds),
+                acquire_time: Some(MicroTime(now)),
+                renew_time: Some(MicroTime(now)),
+                lease_transitions: Some(0),
+                ..Default::default()
+            }),
+        };
+        return match api.create(&PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e.into())
+        };
+    };
+
+    let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+    let held = spec.holder_identity.as_deref() == Some(identity);
+    let expired = spec.renew_time.as_ref().is_none_or(|renewed| {
+        renewed.0 + chrono::Duration::seconds(spec.lease_duration_seconds.unwrap_or(seconds).into()) < now
+    });
+    if !held && !expired {
+        return Ok(false);
+    }
+
+    if !held {
+        spec.holder_identity = Some(identity.to_string());
+        spec.acquire_time = Some(MicroTime(now));
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+    }
+    spec.renew_time = Some(MicroTime(now));
+    spec.lease_duration_seconds = Some(seconds);
+
+    match api.replace(name, &PostParams::default(), &lease).await {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Runs the tasks started by `start` while this replica is the leader, stopping them when
+/// leadership is lost and starting them again when it's regained.
+pub async fn supervise(mut leader: watch::Receiver<bool>, start: impl Fn() -> Vec<JoinHandle<()>>) {
+    loop {
+        if leader.wait_for(|is_leader| *is_leader).await.is_err() {
+            return;
+        }
+        let tasks = start();
+
+        let lost = leader.wait_for(|is_leader| !*is_leader).await;
+        for task in tasks {
+            task.abort();
+        }
+        if lost.is_err() {
+            return;
+        }
+    }
+}
@@ -3,36 +3,84 @@ mod argo;
 mod github;
 mod utils;
 mod helm;
+mod config;
+mod delivery;
+mod registry;
+mod source;
+mod gitlab;
+mod gitea;
+mod project;
+mod compose;
+mod api;
+mod policy;
+mod rollout;
+mod drift;
+mod operator;
+mod leader;
+mod queue;
 
 // Define imports
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
 use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
-use axum::{routing::{post}, http::StatusCode, Json, Router, http::header::HeaderMap};
-use axum::extract::State;
+use axum::{routing::{get, post}, http::StatusCode, Json, Router, http::header::HeaderMap};
+use axum::extract::{Path, State};
 use base64::prelude::*;
-use kube::{Client, Config};
+use kube::{Client, Config, CustomResourceExt};
 use kube::config::{KubeConfigOptions, Kubeconfig};
 use octocrab::Octocrab;
 use serde_json::{json, Value};
+use chrono::{DateTime, Utc};
+use config::ServerConfig;
+use project::{SourceType, SynkronizedProject};
+use delivery::DeliveryLog;
+use registry::RegistryWebhook;
+use source::{CommitStatus, SourceProvider, SourceRepository, Sources, StatusState, StatusTarget};
 
 
 
+/// A published container image, independent of the webhook event that announced it.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Synkronized {
+pub struct PublishedImage {
+    /// Package name, e.g. `synkronized`
     name: String,
-    template: String
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SynkronizedProject {
-    synkronized: Synkronized,
-    config: serde_yaml::Value
+    /// Login of the package owner
+    owner: String,
+    /// Repository holding the synkronized.yaml, when the registry links one
+    repo: Option<SourceRepository>,
+    /// Commit that built the image, when known
+    commit: Option<String>,
+    /// Branch or tag the image was built from, when known
+    git_ref: Option<String>,
+    tag: Option<String>,
+    digest: Option<String>,
+    /// Pullable image reference, e.g. `ghcr.io/vaughnw128/synkronized:latest`
+    url: String,
+    updated_at: Option<DateTime<Utc>>,
 }
 
+impl PublishedImage {
+    /// The image reference without its tag or digest, e.g. `ghcr.io/vaughnw128/synkronized`
+    fn repository(&self) -> &str {
+        let url = self.url.split('@').next().unwrap_or(&self.url);
+        match url.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => repository,
+            _ => url
+        }
+    }
 
+    /// The tag, or the digest of untagged images
+    fn reference(&self) -> &str {
+        self.tag.as_ref().or(self.digest.as_ref()).map(String::as_str).unwrap_or_default()
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ContainerImage {
@@ -40,84 +88,635 @@ pub struct ContainerImage {
     image: String,
 }
 
-#[derive(Clone)]
+/// Each container is the container's own values, plus its `name` and `image`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MultiContainerImages {
+    containers: Vec<serde_yaml::Value>,
+}
+
 struct AppState {
-    github_client: Octocrab,
+    sources: Sources,
     kube_client: Client,
+    config: ServerConfig,
+    deliveries: Mutex<DeliveryLog>,
+    /// Latest rollout of each app, by Application name
+    rollouts: Mutex<HashMap<String, rollout::Rollout>>,
+    /// Latest drift check of each app, by Application name
+    drift: Mutex<HashMap<String, drift::DriftReport>>,
+    in_flight: queue::InFlight,
+    /// Set once a shutdown has started, after which new work is queued rather than run
+    draining: AtomicBool,
 }
 
-async fn registry_published (package_published: github::RegistryPublished, github_client: &Octocrab, kube_client: &Client)  -> Result<StatusCode> {
-    // Pull the Synkronized.yaml file from the repository base
-    let encoded_yaml = match package_published.repository {
-        Some(repo) => github_client.repos(repo.owner.login, repo.name)
-            .get_content()
-            .path("synkronized.yaml")
-            .send()
-            .await?
-            .items[0]
-            .clone()
-            .content
-            .unwrap()
-            .replace("\n", ""),
-        None => return Err(anyhow!("No repository was specified by the webhook."))
-    };
+async fn registry_published (image: &PublishedImage, projects: Vec<SynkronizedProject>, repo: &SourceRepository, source: &dyn SourceProvider, config: &ServerConfig, kube_client: &Client)  -> Result<Vec<argo::Applied>> {
+    let mut rendered = vec![];
+    for project in projects {
+        rendered.push(render_project(project, image, repo, source, config, kube_client).await?);
+    }
 
-    // Decode the synkronized yaml from base64 as this is what Github returns
-    let mut synkronized_yaml: SynkronizedProject = serde_yaml::from_str(&String::from_utf8(BASE64_STANDARD.decode(encoded_yaml)?)?)?;
+    // Nothing is applied unless every app passes the policy
+    if let Some(app) = rendered.iter().find(|app| !app.violations.is_empty()) {
+        return Err(policy::PolicyViolation { app: app.name.clone(), reasons: app.violations.clone() }.into());
+    }
 
-    if package_published.registry_package.package_version.package_url.split(":").collect::<Vec<&str>>()[1] == "" {
-        return Err(anyhow!("Unknown server error."));
+    let mut applied = vec![];
+    for app in &rendered {
+        applied.push(app.application.apply(kube_client, config.apply.force).await?);
     }
 
-    let container_image = serde_yaml::to_value(ContainerImage {
-        name: package_published.registry_package.name,
-        image: package_published.registry_package.package_version.package_url,
-    })?;
+    // Syncs are best effort once every app is applied, so an unreachable Argo API doesn't
+    // leave the remaining apps behind
+    for (app, applied) in rendered.iter().zip(applied.iter_mut()) {
+        if let Err(e) = app.application.sync(kube_client, &config.rollout.sync).await {
+            tracing::warn!("Unable to start a sync of {}: {}", applied.name, e);
+            applied.sync_error = Some(e.to_string());
+        }
+    }
 
-    // Merge the pulled spec yaml, and the name + image
-    utils::merge_yaml(&mut synkronized_yaml.config, container_image);
+    Ok(applied)
+}
 
-    let chart_template = helm::Template::from_chart_repo(&synkronized_yaml.synkronized.template).await?;
+/// Loads the apps declared in `repo` that are bound to the image's package, if any
+async fn bound_projects(image: &PublishedImage, repo: &SourceRepository, source: &dyn SourceProvider, config: &ServerConfig) -> Result<Vec<SynkronizedProject>> {
+    if image.tag.is_none() {
+        return Err(anyhow!("Published image {} has no tag.", image.name));
+    }
+
+    // Pull every app declared in the repository, at the revision that built the image
+    let mut projects = SynkronizedProject::load(source, &repo.name, image, &config.sources).await?;
 
-    let application = argo::Application::create(synkronized_yaml, chart_template);
-    application.apply(kube_client).await?;
+    // Compose services decide which packages an app is bound to, so expand them before selecting
+    for project in projects.iter_mut() {
+        project.load_compose(source, &repo.name).await?;
+    }
 
-    Ok(StatusCode::OK)
+    Ok(SynkronizedProject::select(projects, &image.name))
 }
 
-fn json_error(message: impl Display) -> (StatusCode, Json<Value>) {
-    (StatusCode::BAD_REQUEST, Json(json!(
+/// An Application as it would be applied, with the values it was rendered from
+#[derive(Serialize, Debug)]
+struct RenderedApp {
+    name: String,
+    /// Values merged into the chart, from lowest to highest precedence
+    layers: Vec<ValuesLayer>,
+    /// Reasons the server's policy rejects the app, empty when it may be deployed
+    violations: Vec<String>,
+    application: argo::Application,
+}
+
+#[derive(Serialize, Debug)]
+struct ValuesLayer {
+    /// Where the values come from, e.g. `defaults.owners.vaughnw128` or `config`
+    source: String,
+    values: serde_yaml::Value,
+}
+
+async fn render_project(mut project: SynkronizedProject, image: &PublishedImage, repo: &SourceRepository, source: &dyn SourceProvider, config: &ServerConfig, kube_client: &Client) -> Result<RenderedApp> {
+    // Server defaults go beneath the app's config, most specific last
+    let mut layers: Vec<ValuesLayer> = config.defaults.layers(project.synkronized.template.as_deref(), repo.owner())
+        .into_iter()
+        .map(|(source, values)| ValuesLayer { source, values })
+        .collect();
+    let mut values = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    for layer in &layers {
+        utils::merge_yaml(&mut values, layer.values.clone());
+    }
+    if !project.config.is_null() {
+        layers.push(ValuesLayer { source: "config".to_string(), values: project.config.clone() });
+        utils::merge_yaml(&mut values, project.config);
+    }
+    project.config = values;
+
+    let live = argo::Application::live(kube_client, &project.synkronized.name).await?;
+    let mut images = BTreeMap::new();
+
+    let container_images = match project.synkronized.containers.is_empty() {
+        true => {
+            images.insert(image.name.clone(), image.url.clone());
+            serde_yaml::to_value(ContainerImage {
+                name: image.name.clone(),
+                image: image.url.clone(),
+            })?
+        },
+        false => {
+            // Only the published container changes, the others keep their last deployed image
+            images = live.as_ref().map(argo::Application::images).transpose()?.unwrap_or_default();
+            for container in project.synkronized.containers.iter().filter(|c| c.image.is_none() && c.package() == image.name) {
+                images.insert(container.name.clone(), image.url.clone());
+            }
+
+            let containers: Vec<serde_yaml::Value> = project.synkronized.containers.iter()
+                .filter_map(|c| match c.image.as_ref().or(images.get(&c.name)) {
+                    Some(image) => {
+                        let mut container = serde_yaml::Value::Mapping(c.values.clone());
+                        utils::merge_yaml(&mut container, serde_yaml::to_value(ContainerImage { name: c.name.clone(), image: image.clone() }).ok()?);
+                        Some(container)
+                    },
+                    None => {
+                        tracing::warn!("No image has been published yet for container {} of {}", c.name, project.synkronized.name);
+                        None
+                    }
+                })
+                .collect();
+            serde_yaml::to_value(MultiContainerImages { containers })?
+        }
+    };
+
+    let mut chart_version = None;
+    let argo_sources = match project.synkronized.source {
+        SourceType::Template => {
+            // Merge the pulled spec yaml, and the name + image
+            layers.push(ValuesLayer { source: "image".to_string(), values: container_images.clone() });
+            utils::merge_yaml(&mut project.config, container_images);
+
+            let template = project.synkronized.template.as_ref()
+                .ok_or(anyhow!("synkronized.template is required for template sources."))?;
+            let chart_template = match &project.chart_version {
+                Some(version) => helm::Template { name: template.clone(), version: version.clone() },
+                None => helm::Template::from_chart_repo(template).await?,
+            };
+            chart_version = Some(chart_template.version.clone());
+            let helm = argo::Helm::new(&project.config, &project.synkronized.helm, vec![])?;
+            let template_source = argo::Source::template(chart_template, helm);
+
+            // Values files stay in git, so the Application reads them from the app's repository
+            match project.synkronized.values_files.is_empty() {
+                true => vec![template_source],
+                false => vec![
+                    template_source.with_value_files(&project.synkronized.values_files),
+                    argo::Source::values_ref(source.clone_url(&repo.name), project.git_revision(None)),
+                ]
+            }
+        },
+        SourceType::Chart => {
+            let chart = project.synkronized.chart.clone()
+                .ok_or(anyhow!("synkronized.chart is required for chart sources."))?;
+
+            // Charts in the wild rarely follow the template values layout, so by default the
+            // image goes in through the conventional image.repository / image.tag parameters
+            let parameters = match chart.image_values {
+                true => {
+                    layers.push(ValuesLayer { source: "image".to_string(), values: container_images.clone() });
+                    utils::merge_yaml(&mut project.config, container_images);
+                    vec![]
+                },
+                false => vec![
+                    argo::HelmParameter { name: chart.image_parameters.repository, value: image.repository().to_string(), force_string: false },
+                    argo::HelmParameter { name: chart.image_parameters.tag, value: image.reference().to_string(), force_string: true },
+                ]
+            };
+            let revision = project.git_revision(chart.revision);
+            let helm = argo::Helm::new(&project.config, &project.synkronized.helm, parameters)?;
+            vec![argo::Source::git_chart(source.clone_url(&repo.name), chart.path, revision, helm)]
+        },
+        SourceType::Kustomize => {
+            let kustomize = project.synkronized.kustomize.clone()
+                .ok_or(anyhow!("synkronized.kustomize is required for kustomize sources."))?;
+
+            let names = match kustomize.images.is_empty() {
+                true => vec![image.repository().to_string()],
+                false => kustomize.images
+            };
+            let images = names.iter().map(|name| format!("{}={}", name, image.url)).collect();
+            let revision = project.git_revision(kustomize.revision);
+            vec![argo::Source::kustomize(source.clone_url(&repo.name), kustomize.path, revision, images)]
+        },
+        SourceType::Directory => {
+            let directory = project.synkronized.directory.clone()
+                .ok_or(anyhow!("synkronized.directory is required for directory sources."))?;
+
+            let revision = project.git_revision(directory.revision);
+            vec![argo::Source::directory(source.clone_url(&repo.name), directory.path, revision, argo::Directory {
+                recurse: directory.recurse,
+                include: directory.include,
+                exclude: directory.exclude,
+            })]
+        }
+    };
+
+    let mut application = argo::Application::create(&project, argo_sources);
+    application.record_images(&images);
+    application.record_owner(repo);
+    application.record_published(image);
+    if let Some(version) = &chart_version {
+        application.record_chart_version(version);
+    }
+    if let Some(app) = &project.synkronized_app {
+        application.record_synkronized_app(app);
+    }
+
+    let mut deployed_images: Vec<&str> = images.values().map(String::as_str).collect();
+    deployed_images.extend(project.synkronized.containers.iter().filter_map(|c| c.image.as_deref()));
+    let mut violations = policy::evaluate(&config.policy, &project.synkronized.name, repo.owner(), &project.config, &deployed_images);
+
+    // Apps are named by their config alone, so another repository's app of the same name, or an
+    // Application synkronized never created, is never overwritten
+    if let Some(live) = &live {
+        let transfer = config.ownership.transfers.get(&project.synkronized.name) == Some(repo);
+        match live.owner() {
+            Some(owner) if owner != repo.to_string() && !transfer => {
+                violations.push(format!("Application {} belongs to {}", project.synkronized.name, owner));
+            },
+            None if !transfer && !live.legacy_managed() => {
+                violations.push(format!("Application {} was not created by synkronized", project.synkronized.name));
+            },
+            _ => {}
+        }
+    }
+
+    // A SynkronizedApp only updates its own Application, whatever repository it names
+    if let Some(live) = &live {
+        if live.synkronized_app() != project.synkronized_app.as_deref() {
+            violations.push(match live.synkronized_app() {
+                Some(app) => format!("Application {} belongs to SynkronizedApp {}", project.synkronized.name, app),
+                None => format!("Application {} is not managed by a SynkronizedApp", project.synkronized.name),
+            });
+        }
+    }
+
+    Ok(RenderedApp {
+        name: project.synkronized.name,
+        layers,
+        violations,
+        application,
+    })
+}
+
+fn json_response(status: StatusCode, message: impl Display) -> (StatusCode, Json<Value>) {
+    (status, Json(json!(
         {"message": message.to_string()}
     )))
 }
 
-async fn github_hooks(headers: HeaderMap, State(state): State<Arc<AppState>>, payload: github::WebhookPayload) -> Result<StatusCode, (StatusCode, Json<Value>)> {
-    // Handle ping type events before routing specific payload types
-    if headers.get("X-GitHub-Event")
-        .ok_or(json_error("Expected X-Github-Event"))?
-        .to_str().unwrap() == "ping" {
-        return Ok(StatusCode::OK)
+fn json_error(message: impl Display) -> (StatusCode, Json<Value>) {
+    json_response(StatusCode::BAD_REQUEST, message)
+}
+
+/// Lists what changed in each applied Application, and the SynkronizedApps left to the operator
+fn deployed_response(applied: Vec<argo::Applied>, updated: Vec<String>) -> (StatusCode, Json<Value>) {
+    (StatusCode::OK, Json(json!(
+        {"message": "Deployed", "apps": applied, "synkronizedApps": updated}
+    )))
+}
+
+async fn github_hooks(headers: HeaderMap, State(state): State<Arc<AppState>>, payload: github::WebhookPayload) -> Result<(StatusCode, Json<Value>), (StatusCode, Json<Value>)> {
+    // Only allowed repositories may deploy, whoever holds the webhook secret
+    if let Some(origin) = payload.origin() {
+        if let Err(reason) = policy::check_access(&state.config.access, &origin) {
+            tracing::warn!("Refusing webhook from {:?}: {}", origin, reason);
+            return Err(json_response(StatusCode::FORBIDDEN, format!("Refusing webhook: {}", reason)));
+        }
+    }
+
+    // Ping and unsupported events are acknowledged without touching any deployments
+    let image = match payload {
+        github::WebhookPayload::Ping => return Ok(json_response(StatusCode::OK, "pong")),
+        github::WebhookPayload::Unsupported { event, action } => {
+            tracing::debug!("Ignoring unsupported event {} ({:?})", event, action);
+            return Ok(json_response(StatusCode::ACCEPTED, format!("Unsupported event: {} {}", event, action.unwrap_or_default())))
+        },
+        github::WebhookPayload::RegistryPackagePublished(payload) => PublishedImage::from(*payload),
+        github::WebhookPayload::PackagePublished(payload) => PublishedImage::from(*payload),
+    };
+
+    // GitHub retries failed deliveries and allows manual redelivery, so skip anything already handled
+    let delivery = headers.get("X-GitHub-Delivery")
+        .and_then(|v| v.to_str().ok())
+        .ok_or(json_error("Expected X-GitHub-Delivery"))?
+        .to_string();
+    let processing = match delivery::start(&state.deliveries, &delivery) {
+        Ok(processing) => processing,
+        Err(delivery::Seen::Handled) => {
+            tracing::info!("Skipping duplicate delivery {}", delivery);
+            return Ok(json_response(StatusCode::OK, "Duplicate delivery"))
+        },
+        // The first attempt may still fail, so GitHub is asked to retry later rather than told it succeeded
+        Err(delivery::Seen::InProgress) => {
+            tracing::info!("Delivery {} is already being processed", delivery);
+            return Err(json_response(StatusCode::CONFLICT, "Delivery is already being processed"))
+        },
+    };
+
+    let result = dispatch(&state, vec![image], Some(&delivery)).await;
+
+    // Failed deliveries are forgotten so that a redelivery gets another attempt
+    if result.is_ok() {
+        processing.handled();
+    }
+    result
+}
+
+async fn registry_hooks<P: RegistryWebhook>(State(state): State<Arc<AppState>>, payload: P) -> Result<(StatusCode, Json<Value>), (StatusCode, Json<Value>)> {
+    let images = payload.images(&state.config);
+    if images.is_empty() {
+        return Ok(json_response(StatusCode::ACCEPTED, format!("Nothing to deploy from {} event", P::REGISTRY)))
+    }
+
+    dispatch(&state, images, None).await
+}
+
+/// Deploys the images right away or, when running several replicas, queues them for the leader
+async fn dispatch(state: &Arc<AppState>, images: Vec<PublishedImage>, delivery: Option<&str>) -> Result<(StatusCode, Json<Value>), (StatusCode, Json<Value>)> {
+    for image in &images {
+        check_max_age(&state.config, image.updated_at)?;
+    }
+
+    if state.config.leader_election.enabled || state.draining.load(Ordering::SeqCst) {
+        for image in &images {
+            queue::enqueue(&state.kube_client, &state.config.leader_election.namespace, image, delivery)
+                .await
+                .map_err(|e| {
+                    tracing::error!("Unable to queue deployment of {}: {}", image.url, e);
+                    json_error("Something went wrong!")
+                })?;
+        }
+        return Ok(json_response(StatusCode::ACCEPTED, "Queued for deployment"));
+    }
+
+    let mut applied = vec![];
+    let mut updated = vec![];
+    let mut skipped = vec![];
+    for image in images {
+        let _job = state.in_flight.start(queue::Job::Deploy(image.clone(), false));
+        match deploy(state, image).await? {
+            Deployment::Applied(apps) => applied.extend(apps),
+            Deployment::Updated(apps) => updated.extend(apps),
+            Deployment::Skipped(reason) => skipped.push(reason),
+        }
+    }
+
+    if applied.is_empty() && updated.is_empty() && !skipped.is_empty() {
+        return Ok(json_response(StatusCode::ACCEPTED, format!("Nothing to deploy: {}", skipped.join("; "))));
+    }
+    Ok(deployed_response(applied, updated))
+}
+
+/// Renders the Applications a publish of `image` would deploy, without applying them
+async fn render(_token: api::ApiToken, State(state): State<Arc<AppState>>, Json(mut image): Json<PublishedImage>) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    if image.repo.is_none() {
+        image.repo = state.config.registries.repositories.get(image.repository()).cloned();
+    }
+    let repo = image.repo.clone().ok_or(json_error("No repository was specified."))?;
+    let source = state.sources.get(repo.provider).map_err(json_error)?;
+
+    let projects = bound_projects(&image, &repo, source, &state.config).await.map_err(json_error)?;
+    if projects.is_empty() {
+        return Err(json_error(format!("No app is bound to package {}.", image.name)));
+    }
+    let mut apps = vec![];
+    for project in projects {
+        apps.push(render_project(project, &image, &repo, source, &state.config, &state.kube_client).await.map_err(json_error)?);
+    }
+
+    Ok(Json(json!({"apps": apps})))
+}
+
+/// What a published image led to
+enum Deployment {
+    Applied(Vec<argo::Applied>),
+    /// SynkronizedApps whose image was updated, which the operator deploys in its own time
+    Updated(Vec<String>),
+    /// Why nothing was deployed, without any commit status being reported
+    Skipped(String),
+}
+
+/// Runs a published image through the deploy pipeline, whichever webhook announced it
+async fn deploy(state: &Arc<AppState>, mut image: PublishedImage) -> Result<Deployment, (StatusCode, Json<Value>)> {
+    // Registries without a repository link fall back to the server's image to repository mapping
+    if image.repo.is_none() {
+        image.repo = state.config.registries.repositories.get(image.repository()).cloned();
+    }
+
+    let Some(repo) = image.repo.clone() else {
+        return Err(json_error("No repository was specified by the webhook."))
+    };
+    let source = state.sources.get(repo.provider).map_err(json_error)?;
+
+    // Multi-arch pushes announce each per-platform manifest untagged, against the same commit as
+    // the tagged index, so those are dropped before they can report anything
+    if image.tag.is_none() {
+        tracing::info!("Skipping untagged image {}", image.url);
+        return Ok(Deployment::Skipped(format!("{} has no tag", image.url)));
+    }
+
+    // Report progress against the commit that built the image, when the webhook names one
+    let status_target = image.repo.clone().zip(image.commit.clone())
+        .map(|(repo, commit)| StatusTarget::new(repo, commit, &image.name));
+
+    // Packages of SynkronizedApps are deployed by the operator once their image is updated
+    if state.config.operator.enabled {
+        let updated = operator::publish(&state.kube_client, &image).await.map_err(|e| {
+            tracing::error!("Unable to update SynkronizedApps of {}: {}", image.url, e);
+            json_error("Something went wrong!")
+        })?;
+        if !updated.is_empty() {
+            tracing::info!("Updated the image of SynkronizedApp {} to {}", updated.join(", "), image.url);
+            if let Some(target) = &status_target {
+                report_status(source, target, CommitStatus::new(StatusState::Success, format!("Updated SynkronizedApp {}", updated.join(", ")))).await;
+            }
+            return Ok(Deployment::Updated(updated));
+        }
+    }
+
+    let projects = match bound_projects(&image, &repo, source, &state.config).await {
+        Ok(projects) if projects.is_empty() => {
+            tracing::info!("No app is bound to package {}", image.name);
+            return Ok(Deployment::Skipped(format!("no app is bound to package {}", image.name)));
+        },
+        projects => projects,
     };
 
-    // let payload = match payload {
-    //     Ok(payload) => payload,
-    //     Err(JsonRejection::JsonDataError(_)) => return Err(json_error("The supplied webhook payload type is not accepted.")),
-    //     Err(_) => return Err(json_error("An unknown error has occurred in JSON parsing."))
-    // };
+    if let Some(target) = &status_target {
+        report_status(source, target, CommitStatus::new(StatusState::Pending, format!("Deploying {}", image.url))).await;
+    }
+
+    let result = match projects {
+        Ok(projects) => registry_published(&image, projects, &repo, source, &state.config, &state.kube_client).await,
+        Err(e) => Err(e),
+    };
+    let wait = state.config.rollout.wait && result.is_ok();
+
+    if let Some(target) = &status_target {
+        let status = match &result {
+            Ok(_) if wait => CommitStatus::new(StatusState::Pending, "Applied, waiting for ArgoCD to sync"),
+            Ok(applied) => {
+                let unsynced: Vec<&str> = applied.iter().filter(|app| app.sync_error.is_some()).map(|app| app.name.as_str()).collect();
+                match unsynced.is_empty() {
+                    true => CommitStatus::new(StatusState::Success, "Deployed to ArgoCD"),
+                    false => CommitStatus::new(StatusState::Success, format!("Deployed to ArgoCD, unable to start a sync of {}", unsynced.join(", "))),
+                }
+            },
+            Err(e) => CommitStatus::new(StatusState::Failure, e),
+        };
+        report_status(source, target, status).await;
+    }
+
+    // Webhook senders give up after a few seconds, so the rollout is watched in the background
+    if let (true, Ok(applied)) = (wait, &result) {
+        let apps = applied.iter().map(|app| app.name.clone()).collect();
+        let job = state.in_flight.start(queue::Job::Rollout(status_target.clone()));
+        let state = state.clone();
+        tokio::spawn(async move {
+            watch_rollouts(state, image.url, apps, status_target).await;
+            drop(job);
+        });
+    }
+
+    result.map(Deployment::Applied).map_err(|e| {
+        // Rejections and conflicts are for the repository or an admin to fix, so they are
+        // explained rather than hidden
+        if let Some(violation) = e.downcast_ref::<policy::PolicyViolation>() {
+            tracing::warn!("{}", violation);
+            return json_response(StatusCode::FORBIDDEN, violation);
+        }
+        if let Some(conflict) = e.downcast_ref::<argo::ApplyConflict>() {
+            tracing::warn!("{}", conflict);
+            return json_response(StatusCode::CONFLICT, conflict);
+        }
+
+        tracing::error!("Deployment failed: {}", e);
+        json_error("Something went wrong!")
+    })
+}
+
+/// Waits for Argo to roll out freshly applied apps, recording the outcome and reporting it
+/// against the commit that built the image.
+async fn watch_rollouts(state: Arc<AppState>, image: String, apps: Vec<String>, status_target: Option<StatusTarget>) {
+    let timeout = Duration::from_secs(state.config.rollout.timeout_seconds);
+    let started: Vec<(String, rollout::Rollout)> = apps.into_iter()
+        .map(|name| (name, rollout::Rollout::start(image.clone())))
+        .collect();
+    {
+        let mut rollouts = state.rollouts.lock().unwrap();
+        for (name, rollout) in &started {
+            rollouts.insert(name.clone(), rollout.clone());
+        }
+    }
 
-    // Process different payload types based on enum parsed
-    match payload {
-        github::WebhookPayload::Published(payload) => registry_published(payload, &state.github_client, &state.kube_client).await.map_err(|_| json_error("Something went wrong!"))?
+    let finished = futures::future::join_all(started.into_iter().map(|(name, rollout)| {
+        let client = state.kube_client.clone();
+        async move {
+            let rollout = rollout::wait(&client, &name, rollout, timeout).await;
+            (name, rollout)
+        }
+    })).await;
+
+    let mut failures = vec![];
+    for (name, rollout) in finished {
+        tracing::info!("Rollout of {} to {} finished as {:?}: {}", image, name, rollout.state, rollout.message.as_deref().unwrap_or_default());
+        if rollout.state != rollout::RolloutState::Healthy {
+            failures.push(format!("{} is {:?}: {}", name, rollout.state, rollout.message.as_deref().unwrap_or_default()));
+        }
+        state.rollouts.lock().unwrap().insert(name, rollout);
+    }
+
+    let Some(target) = status_target else {
+        return;
+    };
+    let Ok(source) = state.sources.get(target.repo.provider) else {
+        return;
+    };
+    let status = match failures.is_empty() {
+        true => CommitStatus::new(StatusState::Success, "Synced and healthy in ArgoCD"),
+        false => CommitStatus::new(StatusState::Failure, failures.join("; ")),
     };
+    report_status(source, &target, status).await;
+}
 
-    Ok(StatusCode::OK)
+/// Argo's view of an app next to the images synkronized deployed, and how its last rollout
+/// went when synkronized watched it
+async fn app_status(_token: api::ApiToken, State(state): State<Arc<AppState>>, Path(name): Path<String>) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let application = argo::Application::live(&state.kube_client, &name)
+        .await
+        .map_err(json_error)?
+        .ok_or(json_response(StatusCode::NOT_FOUND, format!("No Application named {}", name)))?;
+    let rollout = state.rollouts.lock().unwrap().get(&name).cloned();
+    let drift = state.drift.lock().unwrap().get(&name).cloned();
+    let images = application.images().map_err(json_error)?;
+
+    Ok(Json(json!({
+        "name": name,
+        "images": images,
+        "status": application.status,
+        "rollout": rollout,
+        "drift": drift,
+    })))
 }
 
+/// The latest drift check of every managed app
+async fn drift_reports(_token: api::ApiToken, State(state): State<Arc<AppState>>) -> Json<Value> {
+    let reports = state.drift.lock().unwrap().clone();
+    Json(json!({"apps": reports}))
+}
+
+/// Drift of each app in the Prometheus text format
+async fn metrics(State(state): State<Arc<AppState>>) -> String {
+    let reports = state.drift.lock().unwrap();
+    let mut apps: Vec<(&String, &drift::DriftReport)> = reports.iter().collect();
+    apps.sort_by_key(|(name, _)| name.as_str());
+
+    let mut output = String::new();
+    output.push_str("# HELP synkronized_app_drifted Whether the live Application differs from its config.\n");
+    output.push_str("# TYPE synkronized_app_drifted gauge\n");
+    for (name, report) in &apps {
+        output.push_str(&format!("synkronized_app_drifted{{app=\"{}\"}} {}\n", name, u8::from(report.drifted())));
+    }
+    output.push_str("# HELP synkronized_app_drift_check_failed Whether the last drift check of the app failed.\n");
+    output.push_str("# TYPE synkronized_app_drift_check_failed gauge\n");
+    for (name, report) in &apps {
+        output.push_str(&format!("synkronized_app_drift_check_failed{{app=\"{}\"}} {}\n", name, u8::from(report.error.is_some())));
+    }
+    output.push_str("# HELP synkronized_app_drift_checked_timestamp_seconds When the app was last checked for drift.\n");
+    output.push_str("# TYPE synkronized_app_drift_checked_timestamp_seconds gauge\n");
+    for (name, report) in &apps {
+        output.push_str(&format!("synkronized_app_drift_checked_timestamp_seconds{{app=\"{}\"}} {}\n", name, report.checked_at.timestamp()));
+    }
+    output
+}
+
+/// Commit statuses are informational, so failing to report one never fails the deployment
+async fn report_status(source: &dyn SourceProvider, target: &StatusTarget, status: CommitStatus) {
+    if let Err(e) = source.report_status(&target.repo.name, &target.commit, &target.context, &status).await {
+        tracing::warn!("Unable to report status to {}: {}", target.repo, e);
+    }
+}
+
+/// Rejects replays of old signed bodies when `webhooks.maxAgeSeconds` is configured
+fn check_max_age(config: &ServerConfig, updated_at: Option<DateTime<Utc>>) -> Result<(), (StatusCode, Json<Value>)> {
+    let Some(max_age) = config.webhooks.max_age_seconds else {
+        return Ok(())
+    };
+
+    match updated_at {
+        Some(updated_at) if (Utc::now() - updated_at).num_seconds() <= max_age => Ok(()),
+        Some(updated_at) => Err(json_error(format!("Payload from {} is older than the allowed {} seconds.", updated_at, max_age))),
+        None => Err(json_error("Payload has no usable updated_at timestamp."))
+    }
+}
+
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the SynkronizedApp CustomResourceDefinition, for `kubectl apply -f -`
+    Crd,
+}
 
 #[tokio::main]
 async fn main() -> Result<()>{
     dotenv().ok();
 
+    if let Some(Command::Crd) = Cli::parse().command {
+        print!("{}", serde_yaml::to_string(&operator::SynkronizedApp::crd())?);
+        return Ok(());
+    }
+
     tracing_subscriber::fmt::init();
 
     // Initialize Octocrab client
@@ -133,16 +732,142 @@ async fn main() -> Result<()>{
     kube_config.accept_invalid_certs = true;
     let kube_client = Client::try_from(kube_config).expect("Could not configure the client.");
 
-    let app_state = Arc::new(AppState { github_client, kube_client });
+    let config = ServerConfig::load()?;
+    let deliveries = Mutex::new(DeliveryLog::new(config.webhooks.delivery_cache_size));
+
+    let sources = Sources::new(github_client, &config.sources)?;
+
+    let rollouts = Mutex::new(HashMap::new());
+    let drift = Mutex::new(HashMap::new());
+
+    let in_flight = queue::InFlight::default();
+    let draining = AtomicBool::new(false);
+
+    let app_state = Arc::new(AppState { sources, kube_client, config, deliveries, rollouts, drift, in_flight, draining });
+
+    // Background loops only run on the leader, which is every replica without leader election
+    let election = app_state.config.leader_election.clone();
+    let (leader, is_leader) = watch::channel(!election.enabled);
+    // A replica without leader election holds on to the sender, so it stays the leader
+    let _leader = match election.enabled {
+        true => {
+            tokio::spawn(leader::run(app_state.kube_client.clone(), election, leader::identity(), leader));
+            None
+        },
+        false => Some(leader)
+    };
+
+    let background = app_state.clone();
+    tokio::spawn(leader::supervise(is_leader, move || {
+        let mut tasks = vec![];
+        if let Some(interval) = background.config.drift.interval_seconds {
+            tasks.push(tokio::spawn(drift::reconcile(background.clone(), Duration::from_secs(interval))));
+        }
+        if background.config.operator.enabled {
+            tasks.push(tokio::spawn(operator::run(background.clone())));
+        }
+        match background.config.leader_election.enabled {
+            true => tasks.push(tokio::spawn(queue::work(background.clone()))),
+            // Only jobs queued by an earlier shutdown can be waiting for a single replica
+            false => {
+                let state = background.clone();
+                tasks.push(tokio::spawn(async move { queue::run_queued(&state).await }));
+            }
+        }
+        tasks
+    }));
 
     let app = Router::new()
         .route("/github-hooks", post(github_hooks))
-        .with_state(app_state);
+        .route("/hooks/dockerhub", post(registry_hooks::<registry::DockerHubPush>))
+        .route("/hooks/harbor", post(registry_hooks::<registry::HarborEvent>))
+        .route("/hooks/oci", post(registry_hooks::<registry::OciNotification>))
+        .route("/hooks/gitlab", post(registry_hooks::<gitlab::PipelineEvent>))
+        .route("/hooks/gitea", post(registry_hooks::<gitea::PackageEvent>))
+        .route("/render", post(render))
+        .route("/apps/{name}/status", get(app_status))
+        .route("/drift", get(drift_reports))
+        .route("/metrics", get(metrics))
+        .with_state(app_state.clone());
 
     // run our app with hyper, listening globally on port 3000
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
     tracing::debug!("listening on {}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
+
+    let (stop, mut stopped) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = stop.send(true);
+    });
+    let mut server_stopped = stopped.clone();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                let _ = server_stopped.wait_for(|stopped| *stopped).await;
+            })
+            .await
+    });
+
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+    app_state.draining.store(true, Ordering::SeqCst);
+    drain(&app_state, server).await;
 
     Ok(())
 }
+
+/// Resolves on SIGTERM or Ctrl+C
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Unable to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Waits for open requests, in-flight deployments and rollout watches to finish, up to
+/// `shutdown.drainSeconds`, then queues the deployments left so they resume on restart.
+async fn drain(state: &Arc<AppState>, server: tokio::task::JoinHandle<std::io::Result<()>>) {
+    let deadline = Duration::from_secs(state.config.shutdown.drain_seconds);
+    tracing::info!("Shutting down, waiting up to {} seconds for in-flight deployments", deadline.as_secs());
+
+    let drained = tokio::time::timeout(deadline, async {
+        if let Ok(Err(e)) = server.await {
+            tracing::error!("Server error while shutting down: {}", e);
+        }
+        while !state.in_flight.is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }).await;
+    if drained.is_ok() {
+        tracing::info!("All deployments finished");
+        return;
+    }
+
+    let namespace = &state.config.leader_election.namespace;
+    for image in state.in_flight.unqueued() {
+        match queue::enqueue(&state.kube_client, namespace, &image, None).await {
+            Ok(_) => tracing::warn!("Queued unfinished deployment of {} to resume on restart", image.url),
+            Err(e) => tracing::error!("Unable to queue unfinished deployment of {}, it is lost: {}", image.url, e),
+        }
+    }
+
+    // Rollout watches aren't resumed, so their commits get a final status rather than staying pending
+    for target in state.in_flight.watched() {
+        if let Ok(source) = state.sources.get(target.repo.provider) {
+            report_status(source, &target, CommitStatus::new(StatusState::Failure, "Interrupted by shutdown before the rollout finished")).await;
+        }
+    }
+}
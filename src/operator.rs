@@ -0,0 +1,212 @@
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+use futures::StreamExt;
+use kube::{Api, Client, CustomResource, ResourceExt};
+use kube::api::{Patch, PatchParams};
+use kube::runtime::Controller;
+use kube::runtime::controller::Action;
+use kube::runtime::watcher;
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use anyhow::anyhow;
+use crate::{render_project, AppState, PublishedImage};
+use crate::argo::FIELD_MANAGER;
+use crate::policy::{self, EventOrigin, PolicyViolation};
+use crate::project::{Synkronized, SynkronizedProject};
+use crate::source::SourceRepository;
+
+/// An app deployed from the cluster rather than from a synkronized.yaml. Publishes of `package`
+/// from `repo` set `image`, and the controller renders it into an Argo Application.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(group = "synkronized.vaughn.sh", version = "v1alpha1", kind = "SynkronizedApp", namespaced, status = "SynkronizedAppStatus", shortname = "skapp")]
+#[serde(rename_all = "camelCase")]
+pub struct SynkronizedAppSpec {
+    /// Repository publishing the app's image, e.g. `vaughnw128/synkronized` or `gitlab:group/project`
+    pub(crate) repo: String,
+    /// Package whose publishes update `image`
+    pub(crate) package: String,
+    /// synkronized-charts template
+    pub(crate) template: String,
+    /// Values of the template, like `config` in synkronized.yaml
+    #[serde(default)]
+    #[schemars(schema_with = "any_object")]
+    pub(crate) config: serde_json::Value,
+    /// Deploys the app as `<name>-<environment>`, so one app can run in several environments
+    pub(crate) environment: Option<String>,
+    /// Image to deploy, set on publish
+    pub(crate) image: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SynkronizedAppStatus {
+    /// Name of the Argo Application
+    pub(crate) application: Option<String>,
+    /// Image the Application was last applied with
+    pub(crate) image: Option<String>,
+    pub(crate) observed_generation: Option<i64>,
+    /// Why the last reconcile failed, if it did
+    pub(crate) error: Option<String>,
+}
+
+/// Free-form values, kept as they are by the API server
+fn any_object(_: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject::default();
+    schema.extensions.insert("x-kubernetes-preserve-unknown-fields".to_string(), json!(true));
+    schema.into()
+}
+
+#[derive(Debug)]
+pub struct ReconcileError(anyhow::Error);
+
+impl Display for ReconcileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ReconcileError {}
+
+impl SynkronizedApp {
+    fn application_name(&self) -> String {
+        match &self.spec.environment {
+            Some(environment) => format!("{}-{}", self.name_any(), environment),
+            None => self.name_any()
+        }
+    }
+
+    /// The publish the app's image stands for
+    fn published_image(&self, repo: &SourceRepository, url: &str) -> PublishedImage {
+        let mut image = PublishedImage {
+            name: self.spec.package.clone(),
+            owner: repo.owner().to_string(),
+            repo: Some(repo.clone()),
+            commit: None,
+            git_ref: None,
+            tag: None,
+            digest: None,
+            url: url.to_string(),
+            updated_at: None,
+        };
+
+        let reference = &url[image.repository().len()..];
+        let (tag, digest) = match reference.split_once('@') {
+            Some((tag, digest)) => (tag, Some(digest.to_string())),
+            None => (reference, None)
+        };
+        image.tag = tag.strip_prefix(':').map(str::to_string);
+        image.digest = digest;
+        image
+    }
+}
+
+/// Points every SynkronizedApp bound to the published package at the new image, returning
+/// their names.
+pub async fn publish(client: &Client, image: &PublishedImage) -> anyhow::Result<Vec<String>> {
+    let Some(repo) = &image.repo else {
+        return Ok(vec![]);
+    };
+    let api: Api<SynkronizedApp> = Api::all(client.clone());
+
+    let mut updated = vec![];
+    for app in api.list(&Default::default()).await? {
+        let bound = app.spec.repo.parse::<SourceRepository>().is_ok_and(|r| &r == repo) && app.spec.package == image.name;
+        if !bound {
+            continue;
+        }
+
+        let namespaced: Api<SynkronizedApp> = Api::namespaced(client.clone(), &app.namespace().unwrap_or_default());
+        let patch = json!({"spec": {"image": image.url}});
+        namespaced.patch(&app.name_any(), &PatchParams { field_manager: Some(FIELD_MANAGER.to_string()), ..Default::default() }, &Patch::Merge(&patch)).await?;
+        updated.push(app.name_any());
+    }
+    Ok(updated)
+}
+
+async fn reconcile(app: Arc<SynkronizedApp>, state: Arc<AppState>) -> Result<Action, ReconcileError> {
+    let result = apply(&app, &state).await;
+
+    let status = SynkronizedAppStatus {
+        application: Some(app.application_name()),
+        image: app.spec.image.clone().filter(|_| result.is_ok()).or(app.status.as_ref().and_then(|s| s.image.clone())),
+        observed_generation: app.metadata.generation,
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    let api: Api<SynkronizedApp> = Api::namespaced(state.kube_client.clone(), &app.namespace().unwrap_or_default());
+    api.patch_status(&app.name_any(), &PatchParams::default(), &Patch::Merge(json!({"status": status})))
+        .await
+        .map_err(|e| ReconcileError(e.into()))?;
+
+    result.map_err(ReconcileError)?;
+    Ok(Action::requeue(Duration::from_secs(3600)))
+}
+
+/// Renders the app into an Application and applies it, like a publish from its repository would
+async fn apply(app: &SynkronizedApp, state: &AppState) -> anyhow::Result<()> {
+    let Some(url) = &app.spec.image else {
+        tracing::info!("{} has no image yet, waiting for a publish", app.name_any());
+        return Ok(());
+    };
+    let repo: SourceRepository = app.spec.repo.parse()?;
+
+    // Creating a SynkronizedApp is no way around the repositories allowed to deploy
+    let origin = EventOrigin { repository: Some(repo.name.clone()), owner: Some(repo.owner().to_string()), ..Default::default() };
+    policy::check_access(&state.config.access, &origin)
+        .map_err(|reason| anyhow!("{} may not deploy: {}", repo, reason))?;
+
+    let source = state.sources.get(repo.provider)?;
+    let image = app.published_image(&repo, url);
+
+    let project = SynkronizedProject {
+        synkronized: Synkronized {
+            name: app.application_name(),
+            template: Some(app.spec.template.clone()),
+            package: Some(app.spec.package.clone()),
+            ..Default::default()
+        },
+        config: serde_json::from_value(app.spec.config.clone())?,
+        revision: None,
+        chart_version: None,
+        synkronized_app: Some(format!("{}/{}", app.namespace().unwrap_or_default(), app.name_any())),
+    };
+
+    let rendered = render_project(project, &image, &repo, source, &state.config, &state.kube_client).await?;
+    if !rendered.violations.is_empty() {
+        return Err(PolicyViolation { app: rendered.name, reasons: rendered.violations }.into());
+    }
+    rendered.application.apply(&state.kube_client, state.config.apply.force).await?;
+
+    // Reconciles also follow restarts, requeues and status updates, only a new image calls for a sync
+    let deployed = app.status.as_ref().and_then(|status| status.image.as_ref());
+    if deployed != Some(url) {
+        rendered.application.sync(&state.kube_client, &state.config.rollout.sync).await?;
+    }
+    Ok(())
+}
+
+fn error_policy(app: Arc<SynkronizedApp>, error: &ReconcileError, _state: Arc<AppState>) -> Action {
+    tracing::warn!("Unable to reconcile SynkronizedApp {}: {}", app.name_any(), error);
+    Action::requeue(Duration::from_secs(60))
+}
+
+/// Reconciles SynkronizedApps in every namespace until the stream ends.
+pub async fn run(state: Arc<AppState>) {
+    let api: Api<SynkronizedApp> = Api::all(state.kube_client.clone());
+    if let Err(e) = api.list(&Default::default()).await {
+        tracing::error!("SynkronizedApp CRD is not installed, operator mode is disabled: {}", e);
+        return;
+    }
+
+    Controller::new(api, watcher::Config::default())
+        .run(reconcile, error_policy, state)
+        .for_each(|result| async move {
+            if let Ok((app, _)) = result {
+                tracing::debug!("Reconciled SynkronizedApp {}", app.name);
+            }
+        })
+        .await;
+}
@@ -0,0 +1,219 @@
+use std::fmt::Display;
+use crate::config::{AccessConfig, AccessList, PolicyConfig};
+
+/// An app that failed the server's policy, with every reason it was rejected.
+#[derive(Debug)]
+pub struct PolicyViolation {
+    pub(crate) app: String,
+    pub(crate) reasons: Vec<String>,
+}
+
+impl Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} was rejected by policy: {}", self.app, self.reasons.join("; "))
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+/// Who a webhook event came from, as far as the event says.
+#[derive(Debug, Default)]
+pub struct EventOrigin {
+    pub(crate) repository: Option<String>,
+    pub(crate) owner: Option<String>,
+    pub(crate) organization: Option<String>,
+    pub(crate) sender: Option<String>,
+}
+
+impl EventOrigin {
+    /// The first entry of `list` naming this origin, described for logs
+    fn matches(&self, list: &AccessList) -> Option<String> {
+        let fields = [
+            ("repository", &self.repository, &list.repositories),
+            ("owner", &self.owner, &list.owners),
+            ("organization", &self.organization, &list.organizations),
+            ("sender", &self.sender, &list.senders),
+        ];
+
+        fields.into_iter().find_map(|(kind, value, entries)| {
+            let value = value.as_ref()?;
+            entries.iter()
+                .any(|entry| entry.eq_ignore_ascii_case(value))
+                .then(|| format!("{} {}", kind, value))
+        })
+    }
+}
+
+/// Whether an event from `origin` may deploy, with the reason when it may not.
+pub fn check_access(access: &AccessConfig, origin: &EventOrigin) -> Result<(), String> {
+    if let Some(denied) = origin.matches(&access.deny) {
+        return Err(format!("{} is denied", denied));
+    }
+
+    let allow = &access.allow;
+    let restricted = !(allow.repositories.is_empty() && allow.owners.is_empty() && allow.organizations.is_empty() && allow.senders.is_empty());
+    if restricted && origin.matches(allow).is_none() {
+        return Err(format!("repository {} is not allowed", origin.repository.as_deref().unwrap_or("(none)")));
+    }
+    Ok(())
+}
+
+/// Checks an app about to be deployed to `namespace` by a repository of `owner`, returning the
+/// reasons it breaks the policy.
+pub fn evaluate(policy: &PolicyConfig, namespace: &str, owner: &str, values: &serde_yaml::Value, images: &[&str]) -> Vec<String> {
+    let mut reasons = vec![];
+
+    if policy.forbidden_namespaces.iter().any(|n| n == namespace) {
+        reasons.push(format!("namespace {} is forbidden", namespace));
+    }
+
+    if !policy.sizes.is_empty() {
+        if let Some(size) = values.get("size").and_then(|v| v.as_str()) {
+            if !policy.sizes.iter().any(|s| s == size) {
+                reasons.push(format!("size {} is not one of {}", size, policy.sizes.join(", ")));
+            }
+        }
+    }
+
+    if !policy.hostnames.is_empty() {
+        if let Some(hostname) = values.get("gateway").and_then(|g| g.get("hostname")).and_then(|h| h.as_str()) {
+            let allowed = policy.hostnames.get(owner).map(Vec::as_slice).unwrap_or_default();
+            if !allowed.iter().any(|pattern| hostname_matches(pattern, hostname)) {
+                reasons.push(format!("hostname {} is not allowed for {}", hostname, owner));
+            }
+        }
+    }
+
+    for image in images {
+        let (registry, image_owner) = image_parts(image);
+        if !policy.registries.is_empty() && !policy.registries.iter().any(|r| r == registry) {
+            reasons.push(format!("registry {} of image {} is not allowed", registry, image));
+        }
+        if !policy.image_owners.is_empty() && !policy.image_owners.iter().any(|o| o == image_owner) {
+            reasons.push(format!("image {} is not owned by an allowed owner", image));
+        }
+    }
+
+    reasons
+}
+
+/// `*.example.com` matches any subdomain of `example.com`, anything else only itself
+fn hostname_matches(pattern: &str, hostname: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let hostname = hostname.to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => hostname.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => pattern == hostname
+    }
+}
+
+/// The registry host and namespace of an image reference, resolving Docker Hub shorthands
+/// such as `postgres:16` to `docker.io` and `library`.
+fn image_parts(image: &str) -> (&str, &str) {
+    let segments: Vec<&str> = image.split('/').collect();
+    let has_registry = segments.len() > 1
+        && (segments[0].contains('.') || segments[0].contains(':') || segments[0] == "localhost");
+
+    match (has_registry, segments.as_slice()) {
+        (true, [registry, owner, _, ..]) => (registry, owner),
+        (true, [registry, _]) => (registry, "library"),
+        (false, [owner, _, ..]) => ("docker.io", owner),
+        _ => ("docker.io", "library")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(repository: Option<&str>, sender: &str) -> EventOrigin {
+        EventOrigin {
+            repository: repository.map(str::to_string),
+            owner: repository.and_then(|r| r.split('/').next()).map(str::to_string),
+            organization: None,
+            sender: Some(sender.to_string()),
+        }
+    }
+
+    fn list(repositories: &[&str], owners: &[&str]) -> AccessList {
+        AccessList {
+            repositories: repositories.iter().map(|r| r.to_string()).collect(),
+            owners: owners.iter().map(|o| o.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn empty_allow_list_is_unrestricted() {
+        assert!(check_access(&AccessConfig::default(), &origin(Some("anyone/app"), "anyone")).is_ok());
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let access = AccessConfig { allow: list(&[], &["vaughnw128"]), deny: list(&["vaughnw128/legacy"], &[]) };
+        assert!(check_access(&access, &origin(Some("vaughnw128/synkronized"), "vaughnw128")).is_ok());
+        assert_eq!(
+            check_access(&access, &origin(Some("vaughnw128/legacy"), "vaughnw128")),
+            Err("repository vaughnw128/legacy is denied".to_string())
+        );
+    }
+
+    #[test]
+    fn senders_can_be_denied() {
+        let access = AccessConfig { deny: AccessList { senders: vec!["mallory".to_string()], ..Default::default() }, ..Default::default() };
+        assert!(check_access(&access, &origin(Some("vaughnw128/synkronized"), "mallory")).is_err());
+    }
+
+    #[test]
+    fn entries_match_case_insensitively() {
+        let access = AccessConfig { allow: list(&["VaughnW128/Synkronized"], &[]), deny: list(&[], &["EVIL"]) };
+        assert!(check_access(&access, &origin(Some("vaughnw128/synkronized"), "vaughnw128")).is_ok());
+        assert!(check_access(&access, &origin(Some("evil/app"), "evil")).is_err());
+    }
+
+    #[test]
+    fn restricted_allow_list_refuses_others() {
+        let access = AccessConfig { allow: list(&[], &["vaughnw128"]), ..Default::default() };
+        assert_eq!(
+            check_access(&access, &origin(Some("someone/app"), "someone")),
+            Err("repository someone/app is not allowed".to_string())
+        );
+    }
+
+    #[test]
+    fn events_without_repository_are_refused_when_owners_are_restricted() {
+        let access = AccessConfig { allow: list(&[], &["vaughnw128"]), ..Default::default() };
+        assert_eq!(
+            check_access(&access, &origin(None, "vaughnw128")),
+            Err("repository (none) is not allowed".to_string())
+        );
+    }
+
+    #[test]
+    fn wildcard_hostnames_match_subdomains_only() {
+        assert!(hostname_matches("*.vaughn.sh", "app.vaughn.sh"));
+        assert!(hostname_matches("*.vaughn.sh", "API.Staging.vaughn.sh"));
+        assert!(!hostname_matches("*.vaughn.sh", "vaughn.sh"));
+        assert!(!hostname_matches("*.vaughn.sh", "evilvaughn.sh"));
+        assert!(!hostname_matches("*.vaughn.sh", "vaughn.sh.evil.com"));
+    }
+
+    #[test]
+    fn exact_hostnames_match_themselves() {
+        assert!(hostname_matches("vaughn.sh", "Vaughn.sh"));
+        assert!(!hostname_matches("vaughn.sh", "app.vaughn.sh"));
+    }
+
+    #[test]
+    fn docker_hub_shorthands() {
+        assert_eq!(image_parts("postgres:16"), ("docker.io", "library"));
+        assert_eq!(image_parts("bitnami/redis:7"), ("docker.io", "bitnami"));
+    }
+
+    #[test]
+    fn registry_hosts() {
+        assert_eq!(image_parts("ghcr.io/vaughnw128/synkronized:latest"), ("ghcr.io", "vaughnw128"));
+        assert_eq!(image_parts("localhost:5000/app"), ("localhost:5000", "library"));
+        assert_eq!(image_parts("localhost/team/app"), ("localhost", "team"));
+    }
+}
@@ -0,0 +1,294 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::argo::{HelmFileParameter, HelmParameter};
+use crate::config::{RefFallback, SourceConfig};
+use crate::source::{NotFound, SourceProvider};
+use crate::compose::ComposeFile;
+use crate::utils::merge_yaml;
+use crate::PublishedImage;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Synkronized {
+    pub(crate) name: String,
+    /// Where the app's manifests come from
+    #[serde(default)]
+    pub(crate) source: SourceType,
+    /// synkronized-charts template, for `template` sources
+    pub(crate) template: Option<String>,
+    /// Values files in the repository layered under `config`, for `template` sources
+    #[serde(default, rename = "valuesFiles")]
+    pub(crate) values_files: Vec<String>,
+    /// Helm settings of `template` and `chart` sources
+    #[serde(default)]
+    pub(crate) helm: HelmOptions,
+    /// Chart in the repository, for `chart` sources
+    pub(crate) chart: Option<ChartSource>,
+    /// Kustomization in the repository, for `kustomize` sources
+    pub(crate) kustomize: Option<KustomizeSource>,
+    /// Plain manifests in the repository, for `directory` sources
+    pub(crate) directory: Option<DirectorySource>,
+    /// Package whose publishes deploy this app, defaults to `name` in multi-app repositories
+    pub(crate) package: Option<String>,
+    /// Containers of a multi-container app, each bound to its own package
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) containers: Vec<Container>,
+    /// Path of a docker-compose.yml whose services become the app's containers
+    pub(crate) compose: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SourceType {
+    /// A template chart from synkronized-charts
+    #[default]
+    Template,
+    /// A Helm chart in the app's own repository
+    Chart,
+    /// A kustomization in the app's own repository
+    Kustomize,
+    /// Plain manifests in the app's own repository
+    Directory,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChartSource {
+    /// Path of the chart in the repository
+    pub(crate) path: String,
+    /// Git revision of the chart, defaults to the revision that built the image
+    pub(crate) revision: Option<String>,
+    /// Inject `name`/`image` into values like templates do, instead of Helm parameters
+    #[serde(default)]
+    pub(crate) image_values: bool,
+    #[serde(default)]
+    pub(crate) image_parameters: ImageParameters,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KustomizeSource {
+    /// Path of the kustomization in the repository
+    pub(crate) path: String,
+    /// Git revision of the kustomization, defaults to the revision that built the image
+    pub(crate) revision: Option<String>,
+    /// Image names in the manifests replaced by the published image, defaults to its repository
+    #[serde(default)]
+    pub(crate) images: Vec<String>,
+}
+
+/// Argo has no image override for plain manifests, so they are deployed at the revision that
+/// built the image and are expected to reference it themselves.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectorySource {
+    /// Path of the manifests in the repository
+    pub(crate) path: String,
+    /// Git revision of the manifests, defaults to the revision that built the image
+    pub(crate) revision: Option<String>,
+    #[serde(default)]
+    pub(crate) recurse: bool,
+    /// Glob of manifests to include, e.g. `*.yaml`
+    pub(crate) include: Option<String>,
+    /// Glob of manifests to exclude
+    pub(crate) exclude: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HelmOptions {
+    /// Pass `config` to Argo as a structured `valuesObject` rather than a YAML string
+    #[serde(default)]
+    pub(crate) values_object: bool,
+    /// Helm release name, defaults to the app's name
+    pub(crate) release_name: Option<String>,
+    #[serde(default)]
+    pub(crate) parameters: Vec<HelmParameter>,
+    #[serde(default)]
+    pub(crate) file_parameters: Vec<HelmFileParameter>,
+    /// Values files relative to the chart, or to the app's repository with a `$values/` prefix
+    #[serde(default)]
+    pub(crate) value_files: Vec<String>,
+    #[serde(default)]
+    pub(crate) ignore_missing_value_files: bool,
+}
+
+/// Names of the Helm parameters set to the published image
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ImageParameters {
+    pub(crate) repository: String,
+    pub(crate) tag: String,
+}
+
+impl Default for ImageParameters {
+    fn default() -> Self {
+        ImageParameters {
+            repository: "image.repository".to_string(),
+            tag: "image.tag".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Container {
+    pub(crate) name: String,
+    /// Package whose publishes update this container, defaults to `name`
+    pub(crate) package: Option<String>,
+    /// Fixed image for containers that aren't published by the repository, e.g. a database
+    pub(crate) image: Option<String>,
+    /// Any other values passed to the chart for this container, e.g. `ports` or `env`
+    #[serde(flatten)]
+    pub(crate) values: serde_yaml::Mapping,
+}
+
+impl Container {
+    pub fn package(&self) -> &str {
+        self.package.as_ref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SynkronizedProject {
+    pub(crate) synkronized: Synkronized,
+    pub(crate) config: serde_yaml::Value,
+    /// Git revision the project was loaded from, `None` for the default branch
+    #[serde(skip)]
+    pub(crate) revision: Option<String>,
+    /// synkronized-charts version to render `template` at, `None` for the latest
+    #[serde(skip)]
+    pub(crate) chart_version: Option<String>,
+    /// SynkronizedApp the project was built from as `namespace/name`, `None` for synkronized.yaml
+    #[serde(skip)]
+    pub(crate) synkronized_app: Option<String>,
+}
+
+/// A synkronized.yaml either describes one app or, in a monorepo, lists several under `apps`.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SynkronizedFile {
+    Apps { apps: Vec<SynkronizedProject> },
+    Single(Box<SynkronizedProject>),
+}
+
+impl SynkronizedFile {
+    fn into_projects(self) -> Vec<SynkronizedProject> {
+        match self {
+            SynkronizedFile::Apps { apps } => apps,
+            SynkronizedFile::Single(project) => vec![*project],
+        }
+    }
+}
+
+impl SynkronizedProject {
+    /// The git revision to deploy repository sources from: a pinned revision, else the one
+    /// the project was loaded from, else the default branch
+    pub fn git_revision(&self, pinned: Option<String>) -> String {
+        pinned.or(self.revision.clone()).unwrap_or("HEAD".to_string())
+    }
+
+    /// Whether publishing `package` should redeploy this app
+    fn binds(&self, package: &str) -> bool {
+        match self.synkronized.containers.is_empty() {
+            true => self.synkronized.package.as_ref().unwrap_or(&self.synkronized.name) == package,
+            false => self.synkronized.containers.iter().any(|c| c.image.is_none() && c.package() == package),
+        }
+    }
+
+    /// Expands the compose file into containers. Containers declared in synkronized.yaml
+    /// with the same name as a service take precedence over what was translated.
+    pub async fn load_compose(&mut self, source: &dyn SourceProvider, repo: &str) -> Result<()> {
+        let Some(path) = &self.synkronized.compose else {
+            return Ok(());
+        };
+        let compose = ComposeFile::load(source, repo, path, self.revision.as_deref()).await?;
+
+        let mut containers = compose.containers();
+        for declared in self.synkronized.containers.drain(..) {
+            match containers.iter_mut().find(|c| c.name == declared.name) {
+                Some(container) => {
+                    let mut values = serde_yaml::Value::Mapping(std::mem::take(&mut container.values));
+                    merge_yaml(&mut values, serde_yaml::Value::Mapping(declared.values));
+                    container.values = values.as_mapping().cloned().unwrap_or_default();
+                    container.package = declared.package.or(container.package.take());
+                    container.image = declared.image.or(container.image.take());
+                },
+                None => containers.push(declared)
+            }
+        }
+        self.synkronized.containers = containers;
+        Ok(())
+    }
+
+    /// Loads every app declared in `repo` at the revision that built `image`, so config and image
+    /// always match, falling back to the image's branch and the default branch as configured when
+    /// the config is missing at that revision.
+    pub async fn load(source: &dyn SourceProvider, repo: &str, image: &PublishedImage, config: &SourceConfig) -> Result<Vec<SynkronizedProject>> {
+        let references = match config.ref_fallback {
+            RefFallback::None => vec![image.commit.as_ref()],
+            RefFallback::Branch | RefFallback::DefaultBranch => vec![image.commit.as_ref(), image.git_ref.as_ref()],
+        };
+        let references: Vec<&String> = references.into_iter().flatten().collect();
+
+        let mut last_error = None;
+        for reference in &references {
+            match SynkronizedProject::load_at(source, repo, Some(reference), config).await {
+                Ok(projects) => return Ok(projects),
+                // Only a config missing at that revision falls back, a broken one fails the deployment
+                Err(e) if e.downcast_ref::<NotFound>().is_some() => {
+                    tracing::warn!("Unable to load synkronized config from {} at {}: {}", repo, reference, e);
+                    last_error = Some(e);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Without any known revision, the default branch is the only option
+        match (config.ref_fallback, last_error) {
+            (RefFallback::DefaultBranch, _) | (_, None) => SynkronizedProject::load_at(source, repo, None, config).await,
+            (_, Some(e)) => Err(e)
+        }
+    }
+
+    /// Reads every YAML file in the config directory when it exists, otherwise the config file.
+    async fn load_at(source: &dyn SourceProvider, repo: &str, reference: Option<&str>, config: &SourceConfig) -> Result<Vec<SynkronizedProject>> {
+        // Only a missing directory means the config file is used, any other error fails the load
+        let files = match source.list_files(repo, &config.config_directory, reference).await {
+            Ok(files) => files,
+            Err(e) if e.downcast_ref::<NotFound>().is_some() => vec![],
+            Err(e) => return Err(e),
+        };
+        let files: Vec<String> = files.into_iter()
+            .filter(|f| f.ends_with(".yaml") || f.ends_with(".yml"))
+            .collect();
+        let files = match files.is_empty() {
+            true => vec![config.config_path.clone()],
+            false => files
+        };
+
+        let mut projects = vec![];
+        for file in files {
+            let contents = source.fetch_file(repo, &file, reference).await?;
+            let parsed: SynkronizedFile = serde_yaml::from_str(&contents)
+                .map_err(|e| anyhow!("Unable to parse {}: {}", file, e))?;
+            projects.extend(parsed.into_projects().into_iter().map(|mut p| {
+                p.revision = reference.map(str::to_string);
+                p
+            }));
+        }
+        Ok(projects)
+    }
+
+    /// Picks the apps bound to a published package. A repository declaring a single app
+    /// without a `package` deploys it for any package, as before monorepo support.
+    pub fn select(projects: Vec<SynkronizedProject>, package: &str) -> Vec<SynkronizedProject> {
+        if let [project] = projects.as_slice() {
+            if project.synkronized.package.is_none() && project.synkronized.containers.is_empty() {
+                return projects;
+            }
+        }
+
+        projects.into_iter()
+            .filter(|p| p.binds(package))
+            .collect()
+    }
+}
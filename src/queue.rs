@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::{Api, Client, ResourceExt};
+use kube::api::{DeleteParams, ListParams, PostParams};
+use crate::{deploy, AppState, PublishedImage};
+use crate::source::StatusTarget;
+
+/// Label marking the ConfigMaps that hold queued deployments
+const JOB_LABEL: &str = "synkronized.vaughn.sh/job";
+const JOB_PREFIX: &str = "synkronized-job-";
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Queues a deployment for the leader as a ConfigMap. Jobs named after a webhook delivery are
+/// only queued once, however many replicas receive the delivery.
+pub async fn enqueue(client: &Client, namespace: &str, image: &PublishedImage, delivery: Option<&str>) -> Result<bool> {
+    let api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+    let job = ConfigMap {
+        metadata: ObjectMeta {
+            name: delivery.map(|d| format!("{}{}", JOB_PREFIX, d.to_lowercase())),
+            generate_name: delivery.is_none().then(|| JOB_PREFIX.to_string()),
+            labels: Some(BTreeMap::from([(JOB_LABEL.to_string(), "true".to_string())])),
+            ..Default::default()
+        },
+        data: Some(BTreeMap::from([("image".to_string(), serde_json::to_string(image)?)])),
+        ..Default::default()
+    };
+
+    match api.create(&PostParams::default(), &job).await {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Deploys queued jobs as they come in, until the replica starts shutting down.
+pub async fn work(state: Arc<AppState>) {
+    while !state.draining.load(Ordering::SeqCst) {
+        run_queued(&state).await;
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Deploys the jobs queued so far, oldest first, removing each once it has been handled. A job
+/// interrupted by a shutdown stays queued and is picked up again on restart.
+pub async fn run_queued(state: &Arc<AppState>) {
+    let api: Api<ConfigMap> = Api::namespaced(state.kube_client.clone(), &state.config.leader_election.namespace);
+    let mut jobs = match api.list(&ListParams::default().labels(JOB_LABEL)).await {
+        Ok(jobs) => jobs.items,
+        Err(e) => {
+            tracing::warn!("Unable to list queued jobs: {}", e);
+            return;
+        }
+    };
+    jobs.sort_by_key(|job| job.creation_timestamp());
+
+    for job in jobs {
+        if state.draining.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let name = job.name_any();
+        match parse(&job) {
+            // Failures are reported by the deployment itself, a retry would fail the same way
+            Ok(image) => {
+                let _job = state.in_flight.start(Job::Deploy(image.clone(), true));
+                let _ = deploy(state, image).await;
+            },
+            Err(e) => tracing::warn!("Dropping malformed job {}: {}", name, e),
+        }
+        if let Err(e) = api.delete(&name, &DeleteParams::default()).await {
+            tracing::warn!("Unable to remove job {}: {}", name, e);
+        }
+    }
+}
+
+/// Work running on this replica, so that shutdown can wait for it and take care of what
+/// doesn't finish in time.
+#[derive(Default, Clone)]
+pub struct InFlight {
+    next: Arc<AtomicU64>,
+    jobs: Arc<Mutex<HashMap<u64, Job>>>,
+}
+
+#[derive(Clone)]
+pub enum Job {
+    /// A deployment of the image, and whether it is already kept in the queue
+    Deploy(PublishedImage, bool),
+    /// A rollout being watched, reported against the commit that built the image when known
+    Rollout(Option<StatusTarget>),
+}
+
+/// Tracks a job until it's dropped
+pub struct InFlightJob {
+    in_flight: InFlight,
+    id: u64,
+}
+
+impl Drop for InFlightJob {
+    fn drop(&mut self) {
+        self.in_flight.jobs.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl InFlight {
+    pub fn start(&self, job: Job) -> InFlightJob {
+        let id = self.next.fetch_add(1, Ordering::SeqCst);
+        self.jobs.lock().unwrap().insert(id, job);
+        InFlightJob { in_flight: self.clone(), id }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.lock().unwrap().is_empty()
+    }
+
+    /// Deployments that would be lost with the process, as they aren't queued
+    pub fn unqueued(&self) -> Vec<PublishedImage> {
+        self.jobs.lock().unwrap()
+            .values()
+            .filter_map(|job| match job {
+                Job::Deploy(image, false) => Some(image.clone()),
+                _ => None
+            })
+            .collect()
+    }
+
+    /// Commits whose rollout is still being watched
+    pub fn watched(&self) -> Vec<StatusTarget> {
+        self.jobs.lock().unwrap()
+            .values()
+            .filter_map(|job| match job {
+                Job::Rollout(target) => target.clone(),
+                _ => None
+            })
+            .collect()
+    }
+}
+
+fn parse(job: &ConfigMap) -> Result<PublishedImage> {
+    let image = job.data.as_ref()
+        .and_then(|data| data.get("image"))
+        .ok_or(anyhow!("no image"))?;
+    Ok(serde_json::from_str(image)?)
+}
@@ -0,0 +1,266 @@
+use axum::body::Bytes;
+use axum::extract::{FromRequest, Request};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use subtle::ConstantTimeEq;
+use crate::{json_error, PublishedImage};
+use crate::config::ServerConfig;
+
+const DOCKER_HUB: &str = "docker.io";
+
+/// Webhooks from registries other than GitHub, each turned into the images it announces.
+pub trait RegistryWebhook {
+    /// Name of the registry, used in logs and responses
+    const REGISTRY: &'static str;
+
+    fn images(self, config: &ServerConfig) -> Vec<PublishedImage>;
+}
+
+/// Compares a shared secret from the request against the one held in `env_var`.
+pub(crate) fn verify_token(provided: Option<&str>, env_var: &str) -> Result<(), (StatusCode, Json<Value>)> {
+    let expected = std::env::var(env_var)
+        .map_err(|_| json_error(format!("{} is not configured, refusing request.", env_var)))?;
+    let provided = provided.ok_or(json_error("Token is missing."))?;
+
+    if provided.as_bytes().ct_ne(expected.as_bytes()).into() {
+        return Err(json_error("Bad token."));
+    }
+    Ok(())
+}
+
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.strip_prefix("Bearer ").unwrap_or(v))
+}
+
+pub(crate) async fn read_body<T: DeserializeOwned, S: Send + Sync>(req: Request, state: &S) -> Result<T, (StatusCode, Json<Value>)> {
+    let body = Bytes::from_request(req, state)
+        .await
+        .map_err(|_| json_error("Error reading request body."))?;
+    let deserializer = &mut serde_json::Deserializer::from_slice(&body);
+
+    serde_path_to_error::deserialize(deserializer)
+        .map_err(|e| json_error(format!("Unable to parse webhook request body: {}", e)))
+}
+
+fn from_unix(timestamp: i64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(timestamp, 0)
+}
+
+/// Docker Hub repository webhook. Docker Hub does not sign deliveries, so the webhook URL
+/// carries a shared `?token=` matched against `DOCKERHUB_WEBHOOK_TOKEN`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerHubPush {
+    pub(crate) callback_url: Option<String>,
+    pub(crate) push_data: DockerHubPushData,
+    pub(crate) repository: DockerHubRepository,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerHubPushData {
+    pub(crate) pushed_at: Option<i64>,
+    pub(crate) pusher: Option<String>,
+    pub(crate) tag: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerHubRepository {
+    pub(crate) name: String,
+    pub(crate) namespace: String,
+    pub(crate) repo_name: String,
+    pub(crate) repo_url: Option<String>,
+}
+
+impl<S> FromRequest<S> for DockerHubPush
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let token = req.uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+            .map(str::to_string);
+        verify_token(token.as_deref(), "DOCKERHUB_WEBHOOK_TOKEN")?;
+
+        read_body(req, state).await
+    }
+}
+
+impl RegistryWebhook for DockerHubPush {
+    const REGISTRY: &'static str = "dockerhub";
+
+    fn images(self, _config: &ServerConfig) -> Vec<PublishedImage> {
+        vec![PublishedImage {
+            name: self.repository.name,
+            owner: self.repository.namespace,
+            repo: None,
+            commit: None,
+            git_ref: None,
+            url: format!("{DOCKER_HUB}/{}:{}", self.repository.repo_name, self.push_data.tag),
+            tag: Some(self.push_data.tag),
+            digest: None,
+            updated_at: self.push_data.pushed_at.and_then(from_unix),
+        }]
+    }
+}
+
+/// Harbor webhook policy payload. Harbor sends the policy's configured auth header verbatim
+/// in `Authorization`, matched against `HARBOR_WEBHOOK_TOKEN`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarborEvent {
+    #[serde(rename = "type")]
+    pub(crate) event_type: String,
+    pub(crate) occur_at: Option<i64>,
+    pub(crate) operator: Option<String>,
+    pub(crate) event_data: HarborEventData,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarborEventData {
+    #[serde(default)]
+    pub(crate) resources: Vec<HarborResource>,
+    pub(crate) repository: HarborRepository,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarborResource {
+    pub(crate) digest: Option<String>,
+    pub(crate) tag: Option<String>,
+    pub(crate) resource_url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarborRepository {
+    pub(crate) name: String,
+    pub(crate) namespace: String,
+    pub(crate) repo_full_name: String,
+    pub(crate) repo_type: Option<String>,
+}
+
+impl<S> FromRequest<S> for HarborEvent
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        verify_token(bearer_token(req.headers()), "HARBOR_WEBHOOK_TOKEN")?;
+
+        read_body(req, state).await
+    }
+}
+
+impl RegistryWebhook for HarborEvent {
+    const REGISTRY: &'static str = "harbor";
+
+    fn images(self, _config: &ServerConfig) -> Vec<PublishedImage> {
+        // Scans, deletions, quota warnings etc. share the endpoint but have nothing to deploy
+        if self.event_type != "PUSH_ARTIFACT" {
+            return vec![];
+        }
+
+        let repository = self.event_data.repository;
+        let updated_at = self.occur_at.and_then(from_unix);
+        self.event_data.resources
+            .into_iter()
+            .filter(|r| r.tag.as_ref().is_some_and(|t| !t.is_empty()))
+            .map(|r| PublishedImage {
+                name: repository.name.clone(),
+                owner: repository.namespace.clone(),
+                repo: None,
+                commit: None,
+                git_ref: None,
+                url: r.resource_url,
+                tag: r.tag,
+                digest: r.digest,
+                updated_at,
+            })
+            .collect()
+    }
+}
+
+/// Notification envelope from an OCI distribution registry (`registry:2`, zot, and most
+/// self-hosted registries). Endpoints are configured with an `Authorization: Bearer` header
+/// matched against `OCI_WEBHOOK_TOKEN`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciNotification {
+    pub(crate) events: Vec<OciEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciEvent {
+    pub(crate) id: String,
+    pub(crate) timestamp: Option<DateTime<Utc>>,
+    pub(crate) action: String,
+    pub(crate) target: OciTarget,
+    pub(crate) request: Option<OciRequest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciTarget {
+    pub(crate) media_type: Option<String>,
+    pub(crate) digest: Option<String>,
+    pub(crate) repository: String,
+    pub(crate) url: Option<String>,
+    pub(crate) tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OciRequest {
+    pub(crate) host: Option<String>,
+}
+
+impl<S> FromRequest<S> for OciNotification
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<Value>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        verify_token(bearer_token(req.headers()), "OCI_WEBHOOK_TOKEN")?;
+
+        read_body(req, state).await
+    }
+}
+
+impl RegistryWebhook for OciNotification {
+    const REGISTRY: &'static str = "oci";
+
+    fn images(self, _config: &ServerConfig) -> Vec<PublishedImage> {
+        // Pulls and blob pushes are also notified; only tagged manifest pushes are deployable
+        self.events
+            .into_iter()
+            .filter(|e| e.action == "push")
+            .filter_map(|e| {
+                let tag = e.target.tag.filter(|t| !t.is_empty())?;
+                let host = e.request.and_then(|r| r.host)?;
+                let (owner, name) = e.target.repository
+                    .rsplit_once('/')
+                    .unwrap_or(("", &e.target.repository));
+
+                Some(PublishedImage {
+                    name: name.to_string(),
+                    owner: owner.to_string(),
+                    repo: None,
+                    commit: None,
+                    git_ref: None,
+                    url: format!("{}/{}:{}", host, e.target.repository, tag),
+                    tag: Some(tag),
+                    digest: e.target.digest,
+                    updated_at: e.timestamp,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use kube::{Api, Client};
+use kube::runtime::wait::await_condition;
+use serde::Serialize;
+use crate::argo::{self, Application, ApplicationStatus, HealthStatusCode, OperationPhase, SyncStatusCode};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RolloutState {
+    /// Applied, and Argo hasn't finished syncing it yet
+    Progressing,
+    /// Synced and healthy
+    Healthy,
+    /// The sync failed or the app became degraded
+    Degraded,
+    /// Neither happened before the timeout
+    TimedOut,
+}
+
+/// How the last deployment of an app rolled out in Argo.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Rollout {
+    pub(crate) image: String,
+    pub(crate) state: RolloutState,
+    pub(crate) message: Option<String>,
+    pub(crate) started_at: DateTime<Utc>,
+    pub(crate) finished_at: Option<DateTime<Utc>>,
+}
+
+impl Rollout {
+    pub fn start(image: String) -> Rollout {
+        Rollout {
+            image,
+            state: RolloutState::Progressing,
+            message: None,
+            started_at: Utc::now(),
+            finished_at: None,
+        }
+    }
+
+    fn finish(self, state: RolloutState, message: Option<String>) -> Rollout {
+        Rollout {
+            state,
+            message,
+            finished_at: Some(Utc::now()),
+            ..self
+        }
+    }
+}
+
+impl ApplicationStatus {
+    /// Where a rollout started at `since` stands. Anything Argo reports from before then
+    /// describes the previous deployment, so it's still progressing until Argo reconciles.
+    fn rollout_state(&self, since: DateTime<Utc>) -> Option<(RolloutState, Option<String>)> {
+        let after = |timestamp: &Option<String>| timestamp.as_deref()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .is_some_and(|t| t.timestamp() >= since.timestamp());
+
+        if let Some(operation) = &self.operation_state {
+            if matches!(operation.phase, OperationPhase::Failed | OperationPhase::Error) && after(&operation.started_at) {
+                return Some((RolloutState::Degraded, operation.message.clone()));
+            }
+        }
+        if !after(&self.reconciled_at) {
+            return None;
+        }
+
+        let sync = self.sync.as_ref().map(|s| s.status);
+        match &self.health {
+            Some(health) if health.status == HealthStatusCode::Degraded => Some((RolloutState::Degraded, health.message.clone())),
+            Some(health) if health.status == HealthStatusCode::Healthy && sync == Some(SyncStatusCode::Synced) => Some((RolloutState::Healthy, None)),
+            _ => None
+        }
+    }
+}
+
+/// Watches the Application until the rollout is healthy or degraded, or `timeout` passes.
+pub async fn wait(client: &Client, name: &str, rollout: Rollout, timeout: Duration) -> Rollout {
+    let api: Api<Application> = Api::namespaced(client.clone(), argo::ARGO_NAMESPACE);
+    let since = rollout.started_at;
+    let settled = move |app: Option<&Application>| app
+        .and_then(|app| app.status.as_ref())
+        .and_then(|status| status.rollout_state(since))
+        .is_some();
+
+    match tokio::time::timeout(timeout, await_condition(api, name, settled)).await {
+        Ok(Ok(app)) => {
+            let (state, message) = app
+                .and_then(|app| app.status)
+                .and_then(|status| status.rollout_state(since))
+                .unwrap_or((RolloutState::Degraded, Some("Application was deleted".to_string())));
+            rollout.finish(state, message)
+        },
+        Ok(Err(e)) => rollout.finish(RolloutState::Degraded, Some(format!("Unable to watch Application: {}", e))),
+        Err(_) => rollout.finish(RolloutState::TimedOut, Some(format!("Not synced and healthy after {} seconds", timeout.as_secs()))),
+    }
+}
@@ -0,0 +1,440 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use octocrab::Octocrab;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use crate::config::SourceConfig;
+
+pub(crate) const STATUS_CONTEXT: &str = "synkronized";
+
+/// Git hosting providers that synkronized.yaml files can be fetched from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    Github,
+    Gitlab,
+    Gitea,
+}
+
+/// A repository on one of the providers, written `owner/name` for GitHub or
+/// `gitlab:group/project` / `gitea:owner/name` for the others.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct SourceRepository {
+    pub(crate) provider: Provider,
+    /// Full path of the repository, e.g. `vaughnw128/synkronized`
+    pub(crate) name: String,
+}
+
+impl SourceRepository {
+    pub fn github(name: impl Into<String>) -> SourceRepository {
+        SourceRepository { provider: Provider::Github, name: name.into() }
+    }
+
+    /// The user or organization owning the repository, or its top-level group on GitLab
+    pub fn owner(&self) -> &str {
+        self.name.split('/').next().unwrap_or_default()
+    }
+}
+
+fn split_repo(repo: &str) -> Result<(&str, &str)> {
+    repo.split_once('/').ok_or(anyhow!("Repository {} is not of the form owner/name.", repo))
+}
+
+impl FromStr for SourceRepository {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (provider, name) = match s.split_once(':') {
+            Some(("github", name)) => (Provider::Github, name),
+            Some(("gitlab", name)) => (Provider::Gitlab, name),
+            Some(("gitea", name)) => (Provider::Gitea, name),
+            Some((provider, _)) => return Err(anyhow!("Unknown source provider {}.", provider)),
+            None => (Provider::Github, s),
+        };
+
+        Ok(SourceRepository { provider, name: name.to_string() })
+    }
+}
+
+impl TryFrom<String> for SourceRepository {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<SourceRepository> for String {
+    fn from(value: SourceRepository) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for SourceRepository {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.provider {
+            Provider::Github => write!(f, "{}", self.name),
+            Provider::Gitlab => write!(f, "gitlab:{}", self.name),
+            Provider::Gitea => write!(f, "gitea:{}", self.name),
+        }
+    }
+}
+
+/// A file missing from a repository, or a revision missing altogether.
+#[derive(Debug)]
+pub struct NotFound {
+    pub(crate) repo: String,
+    pub(crate) path: String,
+}
+
+impl Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} was not found in {}.", self.path, self.repo)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusState {
+    Pending,
+    Success,
+    Failure,
+}
+
+/// A deployment result reported back against the commit that built the image.
+#[derive(Debug, Clone)]
+pub struct CommitStatus {
+    pub(crate) state: StatusState,
+    pub(crate) description: String,
+}
+
+impl CommitStatus {
+    pub fn new(state: StatusState, description: impl Display) -> CommitStatus {
+        // GitHub rejects status descriptions longer than 140 characters
+        let description: String = description.to_string().chars().take(140).collect();
+        CommitStatus { state, description }
+    }
+}
+
+/// The commit a package's deployments are reported against. Each package reports under its own
+/// status context, so images of a monorepo built from one commit don't overwrite each other.
+#[derive(Debug, Clone)]
+pub struct StatusTarget {
+    pub(crate) repo: SourceRepository,
+    pub(crate) commit: String,
+    /// e.g. `synkronized/api`
+    pub(crate) context: String,
+}
+
+impl StatusTarget {
+    pub fn new(repo: SourceRepository, commit: String, package: &str) -> StatusTarget {
+        StatusTarget { repo, commit, context: format!("{}/{}", STATUS_CONTEXT, package) }
+    }
+}
+
+#[async_trait]
+pub trait SourceProvider: Send + Sync {
+    /// Fetches a file from `repo`, at `reference` or the default branch when `None`.
+    async fn fetch_file(&self, repo: &str, path: &str, reference: Option<&str>) -> Result<String>;
+
+    /// Lists the paths of files directly inside `directory`, at `reference` or the default branch,
+    /// failing with `NotFound` when there is no such directory.
+    async fn list_files(&self, repo: &str, directory: &str, reference: Option<&str>) -> Result<Vec<String>>;
+
+    /// Reports a commit status on `sha` under `context`, shown next to the commit in the provider's UI.
+    async fn report_status(&self, repo: &str, sha: &str, context: &str, status: &CommitStatus) -> Result<()>;
+
+    /// HTTPS clone URL of `repo`, as used for Argo git sources.
+    fn clone_url(&self, repo: &str) -> String;
+}
+
+/// A directory entry as returned by the GitLab tree and Gitea contents APIs.
+#[derive(Deserialize)]
+struct TreeEntry {
+    path: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+}
+
+pub struct GitHubSource {
+    client: Octocrab,
+}
+
+#[async_trait]
+impl SourceProvider for GitHubSource {
+    async fn fetch_file(&self, repo: &str, path: &str, reference: Option<&str>) -> Result<String> {
+        let (owner, name) = split_repo(repo)?;
+        let repos = self.client.repos(owner, name);
+        let mut request = repos.get_content().path(path);
+        if let Some(reference) = reference {
+            request = request.r#ref(reference);
+        }
+
+        let not_found = || NotFound { repo: repo.to_string(), path: path.to_string() };
+        let contents = match request.send().await {
+            Ok(contents) => contents,
+            Err(octocrab::Error::GitHub { source, .. }) if source.status_code.as_u16() == 404 => return Err(not_found().into()),
+            Err(e) => return Err(e.into()),
+        };
+        contents.items
+            .first()
+            .and_then(|item| item.decoded_content())
+            .ok_or_else(|| not_found().into())
+    }
+
+    async fn list_files(&self, repo: &str, directory: &str, reference: Option<&str>) -> Result<Vec<String>> {
+        let (owner, name) = split_repo(repo)?;
+        let repos = self.client.repos(owner, name);
+        let mut request = repos.get_content().path(directory);
+        if let Some(reference) = reference {
+            request = request.r#ref(reference);
+        }
+
+        let contents = match request.send().await {
+            Ok(contents) => contents,
+            Err(octocrab::Error::GitHub { source, .. }) if source.status_code.as_u16() == 404 => {
+                return Err(NotFound { repo: repo.to_string(), path: directory.to_string() }.into())
+            },
+            Err(e) => return Err(e.into()),
+        };
+        Ok(contents.items
+            .into_iter()
+            .filter(|item| item.r#type == "file")
+            .map(|item| item.path)
+            .collect())
+    }
+
+    async fn report_status(&self, repo: &str, sha: &str, context: &str, status: &CommitStatus) -> Result<()> {
+        let (owner, name) = split_repo(repo)?;
+        let state = match status.state {
+            StatusState::Pending => octocrab::models::StatusState::Pending,
+            StatusState::Success => octocrab::models::StatusState::Success,
+            StatusState::Failure => octocrab::models::StatusState::Failure,
+        };
+
+        self.client.repos(owner, name)
+            .create_status(sha.to_string(), state)
+            .context(context.to_string())
+            .description(status.description.clone())
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    fn clone_url(&self, repo: &str) -> String {
+        format!("https://github.com/{}.git", repo)
+    }
+}
+
+/// GitLab (gitlab.com or self-hosted) through the v4 REST API, authenticated with
+/// `GITLAB_API_TOKEN`.
+pub struct GitLabSource {
+    url: Url,
+    token: String,
+    http: reqwest::Client,
+}
+
+impl GitLabSource {
+    fn project_url(&self, repo: &str, segments: &[&str]) -> Result<Url> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("GitLab URL {} cannot be a base.", self.url))?
+            .pop_if_empty()
+            .extend(["api", "v4", "projects", repo])
+            .extend(segments);
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl SourceProvider for GitLabSource {
+    async fn fetch_file(&self, repo: &str, path: &str, reference: Option<&str>) -> Result<String> {
+        let url = self.project_url(repo, &["repository", "files", path, "raw"])?;
+        let response = self.http.get(url)
+            .header("PRIVATE-TOKEN", &self.token)
+            .query(&[("ref", reference.unwrap_or("HEAD"))])
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(NotFound { repo: repo.to_string(), path: path.to_string() }.into());
+        }
+        let response = response.error_for_status()
+            .with_context(|| format!("Unable to fetch {} from {}", path, repo))?;
+
+        Ok(response.text().await?)
+    }
+
+    async fn list_files(&self, repo: &str, directory: &str, reference: Option<&str>) -> Result<Vec<String>> {
+        let url = self.project_url(repo, &["repository", "tree"])?;
+        let response = self.http.get(url)
+            .header("PRIVATE-TOKEN", &self.token)
+            .query(&[("path", directory), ("ref", reference.unwrap_or("HEAD"))])
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(NotFound { repo: repo.to_string(), path: directory.to_string() }.into());
+        }
+        let entries: Vec<TreeEntry> = response.error_for_status()?.json().await?;
+
+        Ok(entries.into_iter().filter(|e| e.entry_type == "blob").map(|e| e.path).collect())
+    }
+
+    async fn report_status(&self, repo: &str, sha: &str, context: &str, status: &CommitStatus) -> Result<()> {
+        let url = self.project_url(repo, &["statuses", sha])?;
+        let state = match status.state {
+            StatusState::Pending => "pending",
+            StatusState::Success => "success",
+            StatusState::Failure => "failed",
+        };
+
+        self.http.post(url)
+            .header("PRIVATE-TOKEN", &self.token)
+            .json(&json!({"state": state, "name": context, "description": status.description}))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn clone_url(&self, repo: &str) -> String {
+        format!("{}/{}.git", self.url.as_str().trim_end_matches('/'), repo)
+    }
+}
+
+/// Gitea (and Forgejo) through the v1 REST API, authenticated with `GITEA_API_TOKEN`.
+pub struct GiteaSource {
+    url: Url,
+    token: String,
+    http: reqwest::Client,
+}
+
+impl GiteaSource {
+    fn repo_url(&self, repo: &str, segments: &[&str]) -> Result<Url> {
+        let (owner, name) = split_repo(repo)?;
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Gitea URL {} cannot be a base.", self.url))?
+            .pop_if_empty()
+            .extend(["api", "v1", "repos", owner, name])
+            .extend(segments);
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl SourceProvider for GiteaSource {
+    async fn fetch_file(&self, repo: &str, path: &str, reference: Option<&str>) -> Result<String> {
+        let mut url = self.repo_url(repo, &["raw"])?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Gitea URL {} cannot be a base.", self.url))?
+            .extend(path.split('/'));
+        if let Some(reference) = reference {
+            url.query_pairs_mut().append_pair("ref", reference);
+        }
+
+        let response = self.http.get(url)
+            .header("Authorization", format!("token {}", self.token))
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(NotFound { repo: repo.to_string(), path: path.to_string() }.into());
+        }
+        let response = response.error_for_status()
+            .with_context(|| format!("Unable to fetch {} from {}", path, repo))?;
+
+        Ok(response.text().await?)
+    }
+
+    async fn list_files(&self, repo: &str, directory: &str, reference: Option<&str>) -> Result<Vec<String>> {
+        let mut url = self.repo_url(repo, &["contents"])?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Gitea URL {} cannot be a base.", self.url))?
+            .extend(directory.split('/'));
+        if let Some(reference) = reference {
+            url.query_pairs_mut().append_pair("ref", reference);
+        }
+
+        let response = self.http.get(url)
+            .header("Authorization", format!("token {}", self.token))
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(NotFound { repo: repo.to_string(), path: directory.to_string() }.into());
+        }
+        let entries: Vec<TreeEntry> = response.error_for_status()?.json().await?;
+
+        Ok(entries.into_iter().filter(|e| e.entry_type == "file").map(|e| e.path).collect())
+    }
+
+    async fn report_status(&self, repo: &str, sha: &str, context: &str, status: &CommitStatus) -> Result<()> {
+        let url = self.repo_url(repo, &["statuses", sha])?;
+        let state = match status.state {
+            StatusState::Pending => "pending",
+            StatusState::Success => "success",
+            StatusState::Failure => "failure",
+        };
+
+        self.http.post(url)
+            .header("Authorization", format!("token {}", self.token))
+            .json(&json!({"state": state, "context": context, "description": status.description}))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn clone_url(&self, repo: &str) -> String {
+        format!("{}/{}.git", self.url.as_str().trim_end_matches('/'), repo)
+    }
+}
+
+/// The configured providers. GitHub is always available, GitLab and Gitea only when
+/// their URL is set in the server config.
+pub struct Sources {
+    github: GitHubSource,
+    gitlab: Option<GitLabSource>,
+    gitea: Option<GiteaSource>,
+}
+
+impl Sources {
+    pub fn new(github_client: Octocrab, config: &SourceConfig) -> Result<Sources> {
+        let http = reqwest::Client::new();
+
+        let gitlab = match &config.gitlab.url {
+            Some(url) => Some(GitLabSource {
+                url: Url::parse(url)?,
+                token: std::env::var("GITLAB_API_TOKEN").context("GITLAB_API_TOKEN env variable is required when GitLab is configured")?,
+                http: http.clone(),
+            }),
+            None => None
+        };
+        let gitea = match &config.gitea.url {
+            Some(url) => Some(GiteaSource {
+                url: Url::parse(url)?,
+                token: std::env::var("GITEA_API_TOKEN").context("GITEA_API_TOKEN env variable is required when Gitea is configured")?,
+                http,
+            }),
+            None => None
+        };
+
+        Ok(Sources { github: GitHubSource { client: github_client }, gitlab, gitea })
+    }
+
+    pub fn get(&self, provider: Provider) -> Result<&dyn SourceProvider> {
+        match provider {
+            Provider::Github => Ok(&self.github),
+            Provider::Gitlab => self.gitlab.as_ref()
+                .map(|s| s as &dyn SourceProvider)
+                .ok_or(anyhow!("GitLab is not configured.")),
+            Provider::Gitea => self.gitea.as_ref()
+                .map(|s| s as &dyn SourceProvider)
+                .ok_or(anyhow!("Gitea is not configured.")),
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use serde::Serialize;
+use serde_json::Value;
+
 pub fn merge_yaml(a: &mut serde_yaml::Value, b: serde_yaml::Value) {
     match (a, b) {
         (a @ &mut serde_yaml::Value::Mapping(_), serde_yaml::Value::Mapping(b)) => {
@@ -9,4 +12,37 @@ pub fn merge_yaml(a: &mut serde_yaml::Value, b: serde_yaml::Value) {
         }
         (a, b) => *a = b,
     }
-}
\ No newline at end of file
+}
+
+/// A field that differs between two versions of a resource
+#[derive(Serialize, Debug, Clone)]
+pub struct Change {
+    /// Dotted path of the field, e.g. `spec.source.targetRevision`
+    pub path: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Lists the fields that differ between `before` and `after`, descending into objects.
+pub fn diff_json(path: &str, before: Option<&Value>, after: Option<&Value>, changes: &mut Vec<Change>) {
+    match (before, after) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key)
+                };
+                diff_json(&path, a.get(key), b.get(key), changes);
+            }
+        },
+        (a, b) if a != b => changes.push(Change {
+            path: path.to_string(),
+            before: a.cloned(),
+            after: b.cloned(),
+        }),
+        _ => {}
+    }
+}